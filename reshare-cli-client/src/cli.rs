@@ -1,3 +1,4 @@
+use reshare_models::SortKey;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -16,6 +17,8 @@ pub enum Command {
     Put(PutArgs),
     /// Download files
     Get(GetArgs),
+    /// Remove files
+    Rm(RmArgs),
 }
 
 #[derive(Debug, StructOpt)]
@@ -30,6 +33,30 @@ pub struct ListArgs {
     #[structopt(short, long)]
     /// A key phrase to list files in a private storage
     pub key_phrase: Option<String>,
+
+    #[structopt(long, possible_values = &["name", "size", "upload_date"])]
+    /// Order files by the given field, by name if it's omitted
+    pub sort: Option<SortKey>,
+
+    #[structopt(long)]
+    /// Use descending order
+    pub desc: bool,
+
+    #[structopt(long)]
+    /// List only files which names start with the given prefix
+    /// or match the given glob, e.g. "*.pdf"
+    pub filter: Option<String>,
+
+    #[structopt(long)]
+    /// Max number of files to list
+    pub limit: Option<usize>,
+
+    #[structopt(long)]
+    /// Continue a listing cut off by the limit
+    pub cursor: Option<String>,
+
+    /// Directory to list, all the files are listed if it's omitted
+    pub path: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -38,7 +65,23 @@ pub struct PutArgs {
     /// A key phrase to put files into a hidden private storage
     pub key_phrase: Option<String>,
 
-    /// Paths to files to upload
+    #[structopt(long)]
+    /// Remove files from the server after the given time, e.g. "12h" or "1day"
+    pub ttl: Option<humantime::Duration>,
+
+    #[structopt(long)]
+    /// Remove files from the server once they were downloaded the given number of times
+    pub max_downloads: Option<u32>,
+
+    #[structopt(long)]
+    /// Store files under numbered names instead of adding new versions of existing ones
+    pub keep_both: bool,
+
+    #[structopt(long)]
+    /// Continue an interrupted upload of a single file from the given upload url
+    pub resume: Option<String>,
+
+    /// Paths to files or directories to upload
     pub file_list: Vec<PathBuf>,
 }
 
@@ -48,6 +91,24 @@ pub struct GetArgs {
     /// A key phrase to get files from a private storage
     pub key_phrase: Option<String>,
 
+    #[structopt(long)]
+    /// Version of the files to download, the latest one by default
+    pub version: Option<u32>,
+
     /// File names to download
     pub file_list: Vec<String>,
 }
+
+#[derive(Debug, StructOpt)]
+pub struct RmArgs {
+    #[structopt(short, long)]
+    /// A key phrase to remove files from a private storage
+    pub key_phrase: Option<String>,
+
+    #[structopt(long)]
+    /// Version of the files to remove, all the versions by default
+    pub version: Option<u32>,
+
+    /// File names to remove
+    pub file_list: Vec<String>,
+}
@@ -6,7 +6,9 @@ use crate::utils::{
 use anyhow::{anyhow, bail};
 use bytes::Bytes;
 use futures::{future, Stream, StreamExt};
-use reqwest::{StatusCode, Url};
+use reqwest::{Client, StatusCode, Url};
+use reshare_models::{digest, file_path, DIGEST_HEADER, KEYPHRASE_HEADER};
+use sha2::{Digest, Sha256};
 use tokio::{fs::File, io::AsyncWriteExt, runtime::Runtime};
 
 pub fn execute(args: GetArgs) -> Result<()> {
@@ -18,19 +20,22 @@ pub fn execute(args: GetArgs) -> Result<()> {
         bail!("No files to download");
     }
 
-    let query_url = match args.key_phrase {
-        Some(key_phrase) => server_url
-            .join("api/")?
-            .join("private/")?
-            .join(&format!("{}/", key_phrase))?,
-        None => server_url.join("api/")?.join("download/")?,
-    };
+    let query_url = server_url.join("api/")?.join("download/")?;
+    let key_phrase = args.key_phrase;
+    let version = args.version;
 
     let rt = Runtime::new()?;
-
-    let get_file_info_tasks = file_names
-        .into_iter()
-        .map(|file_name| get_file_info(query_url.clone(), file_name.clone()));
+    let client = Client::new();
+
+    let get_file_info_tasks = file_names.into_iter().map(|file_name| {
+        get_file_info(
+            &client,
+            query_url.clone(),
+            key_phrase.as_deref(),
+            version,
+            file_name,
+        )
+    });
 
     let results = rt.block_on(async move {
         let (files, errors): (Vec<_>, Vec<_>) = future::join_all(get_file_info_tasks)
@@ -73,16 +78,33 @@ pub fn execute(args: GetArgs) -> Result<()> {
 }
 
 async fn get_file_info(
+    client: &Client,
     query_url: Url,
+    key_phrase: Option<&str>,
+    version: Option<u32>,
     file_name: String,
 ) -> Result<FileInfo<impl Stream<Item = ByteChunk>>> {
-    let file_url = query_url.join(&file_name)?;
+    let mut file_url = query_url.join(&file_name)?;
 
-    let response = reqwest::get(file_url).await?;
+    if let Some(version) = version {
+        file_url
+            .query_pairs_mut()
+            .append_pair("version", &version.to_string());
+    }
+
+    let request = client.get(file_url);
+    let request = match key_phrase {
+        Some(key_phrase) => request.header(KEYPHRASE_HEADER, encode_key_phrase(key_phrase)),
+        None => request,
+    };
+
+    let response = request.send().await?;
 
     if !response.status().is_success() {
         if response.status() == StatusCode::NOT_FOUND {
             bail!("{} not found", file_name);
+        } else if response.status() == StatusCode::GONE {
+            bail!("{} has reached its download limit", file_name);
         } else {
             let contents = response.text().await?;
             bail!("{}", contents);
@@ -93,10 +115,17 @@ async fn get_file_info(
         .content_length()
         .ok_or_else(|| anyhow!("{} - unknown file size", file_name))?;
 
+    let digest = response
+        .headers()
+        .get(DIGEST_HEADER)
+        .and_then(|digest| digest.to_str().ok())
+        .and_then(digest::from_header);
+
     Ok(FileInfo {
         name: file_name,
         stream: response.bytes_stream(),
         len: file_len,
+        digest,
     })
 }
 
@@ -106,26 +135,68 @@ async fn download_file<S: Stream<Item = ByteChunk> + Unpin>(
 ) -> Result<()> {
     let file_name = file_info.name.clone();
 
-    let mut file = create_file(file_info.name).await?;
+    let (mut file, path) = create_file(file_info.name).await?;
     let (mut stream, monitor) = MonitoredStream::new(file_info.stream);
 
-    ChanConnector::connect_with(monitor, reporter, move |bytes_written| ProgressUpdate {
-        file_name: file_name.clone(),
-        bytes_transmitted: bytes_written,
+    ChanConnector::connect_with(monitor, reporter, move |bytes_written| {
+        ProgressUpdate::Transmitted {
+            file_name: file_name.clone(),
+            bytes: bytes_written,
+        }
     })
     .seal();
 
+    let mut hasher = Sha256::new();
+    let mut bytes_written: u64 = 0;
+
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        bytes_written += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+
+    let corruption = if bytes_written != file_info.len {
+        Some(format!(
+            "received {} of {} bytes",
+            bytes_written, file_info.len
+        ))
+    } else {
+        file_info
+            .digest
+            .filter(|digest| digest.as_slice() != hasher.finalize().as_slice())
+            .map(|_| "contents don't match the digest sent by the server".to_owned())
+    };
+
+    if let Some(reason) = corruption {
+        let _ = tokio::fs::remove_file(&path).await;
+        bail!("{} is corrupted, {}", path, reason);
     }
 
     Ok(())
 }
 
-async fn create_file(file_name: String) -> Result<File> {
+/// Creates the file and returns it along with the path it's created at
+async fn create_file(file_name: String) -> Result<(File, String)> {
     use std::io::ErrorKind;
 
+    // Names are normalized the same way the server does on upload,
+    // so files stored before it can't be written outside the current directory
+    let local_path: std::path::PathBuf = file_path::normalize(&file_name)
+        .ok_or_else(|| anyhow!("{} - unsafe file name", file_name))?
+        .split(file_path::SEPARATOR)
+        .collect();
+    let file_name = local_path.to_string_lossy().into_owned();
+
+    // Files from subdirectories are placed into the same directories locally
+    if let Some(dir) = local_path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| anyhow!("{} - {}", file_name, e))?;
+    }
+
     for file_name in
         std::iter::once(file_name.clone()).chain((1..).map(|num| format!("{}({})", file_name, num)))
     {
@@ -133,7 +204,11 @@ async fn create_file(file_name: String) -> Result<File> {
             Err(e) if e.kind() == ErrorKind::AlreadyExists || e.kind() == ErrorKind::Other => {
                 continue
             }
-            result => return result.map_err(|e| anyhow!("{} - {}", file_name, e)),
+            result => {
+                return result
+                    .map(|file| (file, file_name.clone()))
+                    .map_err(|e| anyhow!("{} - {}", file_name, e))
+            }
         }
     }
 
@@ -146,6 +221,8 @@ struct FileInfo<S> {
     name: String,
     stream: S,
     len: u64,
+    /// SHA-256 digest of the contents if the server knows it
+    digest: Option<Vec<u8>>,
 }
 
 impl<S> std::fmt::Debug for FileInfo<S> {
@@ -1,30 +1,78 @@
 use super::*;
 
 use comfy_table::Table;
-use reshare_models::{Error, FileInfo};
+use reshare_models::{
+    DirListing, Error, FileInfo, SortOrder, KEYPHRASE_HEADER, NEXT_CURSOR_HEADER,
+};
 use std::iter::FromIterator;
 
 pub fn execute(list: ListArgs) -> Result<()> {
     let server_url = load_configuration()?;
 
-    let query_url = server_url.join("api/")?;
-    let query_url = match list.key_phrase {
-        Some(key_phrase) => query_url.join("private/")?.join(&key_phrase)?,
-        None => query_url.join("list")?,
+    let mut query_url = server_url.join("api/")?.join("list")?;
+
+    {
+        let mut query = query_url.query_pairs_mut();
+
+        if let Some(path) = &list.path {
+            query.append_pair("path", path);
+        }
+
+        if let Some(sort) = list.sort {
+            query.append_pair("sort", sort.as_str());
+        }
+
+        if list.desc {
+            query.append_pair("order", SortOrder::Desc.as_str());
+        }
+
+        if let Some(filter) = &list.filter {
+            query.append_pair("filter", filter);
+        }
+
+        if let Some(limit) = list.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+
+        if let Some(cursor) = &list.cursor {
+            query.append_pair("cursor", cursor);
+        }
+    }
+
+    let request = http::Client::new().get(query_url.clone());
+    let request = match list.key_phrase {
+        Some(key_phrase) => request.header(KEYPHRASE_HEADER, encode_key_phrase(&key_phrase)),
+        None => request,
     };
 
-    let resp = http::get(query_url.clone()).context(format!("Failure quering {}", query_url))?;
+    let resp = request
+        .send()
+        .context(format!("Failure quering {}", query_url))?;
 
     if resp.status().is_success() {
-        let files: Vec<FileInfo> = resp.json()?;
+        let next_cursor = resp
+            .headers()
+            .get(NEXT_CURSOR_HEADER)
+            .and_then(|cursor| cursor.to_str().ok())
+            .map(str::to_owned);
 
-        let table: FilesTableView = files.into_iter().collect();
+        let table: FilesTableView = match list.path {
+            Some(_) => resp.json::<DirListing>()?.into(),
+            None => resp.json::<Vec<FileInfo>>()?.into_iter().collect(),
+        };
 
         if table.is_empty() {
             println!("No files are currently available");
         } else {
             println!("{}", table);
         }
+
+        if let Some(cursor) = next_cursor {
+            println!(
+                "More files are available, use --cursor {} to list them",
+                cursor
+            );
+        }
     } else {
         let error: Error = resp.json()?;
         anyhow::bail!("{}", error.error_msg)
@@ -45,12 +93,11 @@ impl FilesTableView {
     }
 }
 
-impl FromIterator<FileInfo> for FilesTableView {
-    fn from_iter<I: IntoIterator<Item = FileInfo>>(iter: I) -> Self {
+impl FilesTableView {
+    fn new() -> Self {
         use comfy_table::modifiers::UTF8_ROUND_CORNERS;
         use comfy_table::presets::UTF8_FULL;
         use comfy_table::*;
-        use indicatif::HumanBytes;
 
         let mut table = Table::new();
 
@@ -64,24 +111,81 @@ impl FromIterator<FileInfo> for FilesTableView {
                 Cell::new("Size")
                     .set_alignment(CellAlignment::Center)
                     .add_attribute(Attribute::Bold),
+                Cell::new("Type")
+                    .set_alignment(CellAlignment::Center)
+                    .add_attribute(Attribute::Bold),
                 Cell::new("Upload date")
                     .set_alignment(CellAlignment::Center)
                     .add_attribute(Attribute::Bold),
             ]);
 
-        let mut rows_count = 0;
+        Self {
+            table,
+            rows_count: 0,
+        }
+    }
+
+    fn add_row(&mut self, name: String, size: String, content_type: String, date: String) {
+        use comfy_table::{Cell, CellAlignment};
+
+        self.table.add_row(vec![
+            Cell::new(name).set_alignment(CellAlignment::Center),
+            Cell::new(size).set_alignment(CellAlignment::Center),
+            Cell::new(content_type).set_alignment(CellAlignment::Center),
+            Cell::new(date).set_alignment(CellAlignment::Center),
+        ]);
+        self.rows_count += 1;
+    }
+
+    fn add_file(&mut self, name: String, file_info: &FileInfo) {
+        use indicatif::HumanBytes;
+
+        let human_readable_size = HumanBytes(file_info.size).to_string();
+        let human_readable_date = file_info.upload_date.format("%b %d, %H:%M").to_string();
+        let content_type = file_info
+            .content_type
+            .clone()
+            .unwrap_or_else(|| "-".to_owned());
+        self.add_row(name, human_readable_size, content_type, human_readable_date);
+    }
+}
+
+impl FromIterator<FileInfo> for FilesTableView {
+    fn from_iter<I: IntoIterator<Item = FileInfo>>(iter: I) -> Self {
+        let mut table = Self::new();
+
         for item in iter {
-            let human_readable_size = HumanBytes(item.size);
-            let human_readable_date = item.upload_date.format("%b %d, %H:%M").to_string();
-            table.add_row(vec![
-                Cell::new(item.name).set_alignment(CellAlignment::Center),
-                Cell::new(human_readable_size).set_alignment(CellAlignment::Center),
-                Cell::new(human_readable_date).set_alignment(CellAlignment::Center),
-            ]);
-            rows_count += 1;
+            table.add_file(item.name.clone(), &item);
         }
 
-        Self { table, rows_count }
+        table
+    }
+}
+
+impl From<DirListing> for FilesTableView {
+    fn from(listing: DirListing) -> Self {
+        let mut table = Self::new();
+
+        for dir in listing.dirs {
+            table.add_row(
+                format!("{}/", dir),
+                "-".to_owned(),
+                "-".to_owned(),
+                "-".to_owned(),
+            );
+        }
+
+        for item in listing.files {
+            let name = item
+                .name
+                .rsplit('/')
+                .next()
+                .unwrap_or(&item.name)
+                .to_owned();
+            table.add_file(name, &item);
+        }
+
+        table
     }
 }
 
@@ -2,8 +2,9 @@ pub mod config;
 pub mod get;
 pub mod list;
 pub mod put;
+pub mod rm;
 
-use super::cli::{ConfigArgs, GetArgs, ListArgs, PutArgs};
+use super::cli::{ConfigArgs, GetArgs, ListArgs, PutArgs, RmArgs};
 use super::Result;
 use anyhow::Context;
 use reqwest::blocking as http;
@@ -11,6 +12,11 @@ use reqwest::Url;
 
 const CONFIG_FILE_NAME: &str = "reshare-url";
 
+/// Keyphrases may contain characters that are not allowed in headers
+fn encode_key_phrase(key_phrase: &str) -> String {
+    urlencoding::encode(key_phrase)
+}
+
 type Configuration = Url;
 
 fn configure(server_addr: &str) -> Result<()> {
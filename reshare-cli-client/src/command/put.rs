@@ -1,37 +1,61 @@
 use super::*;
 use crate::utils::{
+    digest::file_digest,
     progress_tracker::{ProgressReporter, ProgressTracker, ProgressUpdate},
     ChanConnector, MonitoredStream,
 };
 use anyhow::{anyhow, bail};
 use futures::future;
-use reqwest::{
-    multipart::{Form, Part},
-    Body,
-};
-use reshare_models::FileUploadStatus;
-use std::{
-    convert::{TryFrom, TryInto},
-    path::PathBuf,
-};
+use reqwest::{header, Body, Client, RequestBuilder, Response, StatusCode};
+use reshare_models::tus::*;
+use reshare_models::{digest, Error, KEYPHRASE_HEADER};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncSeekExt;
 use tokio::{fs::File, runtime as rt};
 use tokio_util::codec::{BytesCodec, FramedRead};
 
+/// Attempts to resume an upload after the connection breaks
+const MAX_RETRIES: u32 = 5;
+/// Delay before the first retry, doubled for every next one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
 pub fn execute(args: PutArgs) -> Result<()> {
     let server_url = load_configuration()?;
 
-    let files: Vec<FileRef> = args
-        .file_list
-        .into_iter()
-        .filter_map(|path| path.try_into().ok())
-        .collect();
+    let files: Vec<FileRef> = args.file_list.into_iter().flat_map(collect_files).collect();
 
     if files.is_empty() {
         bail!("No files to upload");
     }
 
-    let query_url = server_url.join("api/")?.join("upload")?;
-    let key_phrase = args.key_phrase;
+    let resume_url = match &args.resume {
+        Some(_) if files.len() != 1 => bail!("Only a single file upload can be resumed"),
+        Some(url) => Some(Url::parse(url)?),
+        None => None,
+    };
+
+    let query_url = server_url.join("api/")?.join("tus")?;
+
+    let mut metadata = Vec::new();
+    if let Some(ttl) = args.ttl {
+        metadata.push((TTL_KEY, ttl.as_secs().to_string()));
+    }
+    if let Some(max_downloads) = args.max_downloads {
+        metadata.push((MAX_DOWNLOADS_KEY, max_downloads.to_string()));
+    }
+    let on_conflict = if args.keep_both {
+        "keep_both"
+    } else {
+        "new_version"
+    };
+    metadata.push((ON_CONFLICT_KEY, on_conflict.to_owned()));
+
+    let upload = Upload {
+        client: Client::new(),
+        key_phrase: args.key_phrase,
+    };
 
     let mut upload_tracker = ProgressTracker::new();
 
@@ -42,10 +66,11 @@ pub fn execute(args: PutArgs) -> Result<()> {
     let upload_tasks: Vec<_> = files
         .iter()
         .map(|file_ref| {
-            file_upload_task(
+            upload.file_upload_task(
                 query_url.clone(),
+                resume_url.clone(),
                 file_ref.clone(),
-                key_phrase.clone(),
+                &metadata,
                 upload_tracker.get_reporter(),
             )
         })
@@ -62,62 +87,233 @@ pub fn execute(args: PutArgs) -> Result<()> {
     });
 
     for (res, file) in results.iter().zip(files.iter()) {
-        match res {
-            Ok(FileUploadStatus::Error(error_msg)) => {
-                println!("{} - Error while uploading file: {}", file.name, error_msg);
-            }
-            Err(e) => println!("{} - operation failed. {}", file.name, e),
-            _ => (),
+        if let Err(e) = res {
+            println!("{} - Error while uploading file: {}", file.name, e);
         }
     }
 
     Ok(())
 }
 
-async fn file_upload_task(
-    url: Url,
-    file_ref: FileRef,
-    keyphrase: Option<String>,
-    progress_reporter: ProgressReporter,
-) -> Result<FileUploadStatus> {
-    let file = File::open(file_ref.path).await?;
+/// Uploads files with the tus protocol, so that they're resumed
+/// from the server-reported offset when the connection breaks
+struct Upload {
+    client: Client,
+    key_phrase: Option<String>,
+}
+
+impl Upload {
+    async fn file_upload_task(
+        &self,
+        tus_url: Url,
+        resume_url: Option<Url>,
+        file_ref: FileRef,
+        metadata: &[(&str, String)],
+        progress_reporter: ProgressReporter,
+    ) -> Result<()> {
+        // Lets the server detect contents corrupted in transit
+        let digest = file_digest(file_ref.path.clone()).await?;
+        let metadata: Vec<_> = metadata
+            .iter()
+            .cloned()
+            .chain(std::iter::once((DIGEST_KEY, digest::to_header(&digest))))
+            .collect();
+
+        let mut resuming = resume_url.is_some();
+        let upload_url = match resume_url {
+            Some(url) => url,
+            None => self.create(tus_url, &file_ref, &metadata).await?,
+        };
+
+        let mut retries = 0;
+
+        loop {
+            let attempt = if resuming {
+                self.resume(&upload_url, &file_ref, &progress_reporter)
+                    .await
+            } else {
+                self.append(&upload_url, &file_ref, 0, progress_reporter.clone())
+                    .await
+            };
+
+            let error = match attempt {
+                Ok(offset) if offset >= file_ref.len => return Ok(()),
+                // The server stopped reading early, e.g. the connection broke on its side
+                Ok(offset) => anyhow!("Upload stopped at {} of {} bytes", offset, file_ref.len),
+                Err(e) if is_transient(&e) => e,
+                Err(e) => return Err(interrupted(&upload_url, &file_ref, e)),
+            };
+
+            if retries == MAX_RETRIES {
+                return Err(interrupted(&upload_url, &file_ref, error));
+            }
+
+            tokio::time::sleep(RETRY_DELAY * 2u32.pow(retries)).await;
+            retries += 1;
+            resuming = true;
+        }
+    }
+
+    /// Creates the upload and returns its url
+    async fn create(
+        &self,
+        tus_url: Url,
+        file_ref: &FileRef,
+        metadata: &[(&str, String)],
+    ) -> Result<Url> {
+        let metadata = std::iter::once((FILENAME_KEY, file_ref.name.clone()))
+            .chain(metadata.iter().cloned())
+            .map(|(key, value)| format!("{} {}", key, base64::encode(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let response = self
+            .request(self.client.post(tus_url.clone()))
+            .header(UPLOAD_LENGTH_HEADER, file_ref.len)
+            .header(UPLOAD_METADATA_HEADER, metadata)
+            .send()
+            .await?;
+        let response = ensure_success(response).await?;
+
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow!("Unexpected response from the server"))?;
+
+        Ok(tus_url.join(location)?)
+    }
+
+    /// Queries the offset the server has received and continues from there
+    async fn resume(
+        &self,
+        upload_url: &Url,
+        file_ref: &FileRef,
+        progress_reporter: &ProgressReporter,
+    ) -> Result<u64> {
+        let response = self
+            .request(self.client.head(upload_url.clone()))
+            .header(header::CACHE_CONTROL, "no-store")
+            .send()
+            .await?;
+        let offset = upload_offset(&ensure_success(response).await?)?;
+
+        let _ = progress_reporter
+            .send(ProgressUpdate::Restarted {
+                file_name: file_ref.name.clone(),
+                position: offset,
+            })
+            .await;
+
+        if offset >= file_ref.len {
+            return Ok(offset);
+        }
+
+        self.append(upload_url, file_ref, offset, progress_reporter.clone())
+            .await
+    }
+
+    /// Sends the rest of the file starting at `offset` and returns the new offset
+    async fn append(
+        &self,
+        upload_url: &Url,
+        file_ref: &FileRef,
+        offset: u64,
+        progress_reporter: ProgressReporter,
+    ) -> Result<u64> {
+        let mut file = File::open(&file_ref.path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
 
-    let file_stream = FramedRead::new(file, BytesCodec::new());
-    let (file_stream, monitor) = MonitoredStream::new(file_stream);
+        let file_stream = FramedRead::new(file, BytesCodec::new());
+        let (file_stream, monitor) = MonitoredStream::new(file_stream);
 
-    let file_name = file_ref.name.clone();
+        let file_name = file_ref.name.clone();
 
-    ChanConnector::connect_with(monitor, progress_reporter, move |bytes_read| {
-        ProgressUpdate {
-            file_name: file_name.clone(),
-            bytes_transmitted: bytes_read,
+        ChanConnector::connect_with(monitor, progress_reporter, move |bytes_read| {
+            ProgressUpdate::Transmitted {
+                file_name: file_name.clone(),
+                bytes: bytes_read,
+            }
+        })
+        .seal();
+
+        let response = self
+            .request(self.client.patch(upload_url.clone()))
+            .header(UPLOAD_OFFSET_HEADER, offset)
+            .header(header::CONTENT_TYPE, OFFSET_CONTENT_TYPE)
+            .header(header::CONTENT_LENGTH, file_ref.len - offset)
+            .body(Body::wrap_stream(file_stream))
+            .send()
+            .await?;
+
+        upload_offset(&ensure_success(response).await?)
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header(TUS_RESUMABLE_HEADER, TUS_VERSION);
+
+        match &self.key_phrase {
+            Some(key_phrase) => request.header(KEYPHRASE_HEADER, encode_key_phrase(key_phrase)),
+            None => request,
         }
-    })
-    .seal();
-
-    let file_part = Part::stream_with_length(Body::wrap_stream(file_stream), file_ref.len)
-        .file_name(file_ref.name.clone());
-
-    let form = Form::new()
-        .text("keyphrase", keyphrase.unwrap_or_default())
-        .part("file", file_part);
-
-    let response = reqwest::Client::new()
-        .post(url)
-        .multipart(form)
-        .send()
-        .await?
-        .json::<Vec<FileUploadStatus>>()
-        .await?;
-
-    // There will be only 1 element in the vec
-    // as we send only 1 file per task
+    }
+}
+
+/// Error response of the server
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+struct StatusError {
+    status: StatusCode,
+    message: String,
+}
+
+async fn ensure_success(response: Response) -> Result<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let message = match response.json::<Error>().await {
+        Ok(error) => error.error_msg,
+        Err(_) => format!("Server responded with {}", status),
+    };
+
+    Err(StatusError { status, message }.into())
+}
+
+/// The partial upload is kept on the server until it's reaped,
+/// so that it can be resumed later
+fn interrupted(upload_url: &Url, file_ref: &FileRef, error: anyhow::Error) -> anyhow::Error {
+    anyhow!(
+        "{}\nResume the upload with `reshare put --resume {} {}`",
+        error,
+        upload_url,
+        file_ref.path.display()
+    )
+}
+
+fn upload_offset(response: &Response) -> Result<u64> {
     response
-        .into_iter()
-        .next()
+        .headers()
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|offset| offset.to_str().ok())
+        .and_then(|offset| offset.parse().ok())
         .ok_or_else(|| anyhow!("Unexpected response from the server"))
 }
 
+/// Whether the request failed on the way and may succeed if repeated.
+/// Conflicts are expected when the server hasn't noticed the broken
+/// connection yet, the offset is queried again before retrying
+fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<StatusError>() {
+        return e.status == StatusCode::CONFLICT || e.status == StatusCode::LOCKED;
+    }
+
+    e.downcast_ref::<reqwest::Error>()
+        .map(|e| e.is_connect() || e.is_timeout() || e.is_request() || e.is_body())
+        .unwrap_or(false)
+}
+
 #[derive(Debug, Clone)]
 struct FileRef {
     name: String,
@@ -125,20 +321,44 @@ struct FileRef {
     path: PathBuf,
 }
 
-impl TryFrom<PathBuf> for FileRef {
-    type Error = ();
-
-    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
-        let metadata = std::fs::metadata(&path).map_err(drop)?;
-        Ok(FileRef {
-            name: path
-                .as_path()
-                .file_name()
-                .ok_or(())?
-                .to_string_lossy()
-                .into_owned(),
+/// Directories are uploaded along with all their contents,
+/// the files are named by paths relative to the directory's parent
+fn collect_files(path: PathBuf) -> Vec<FileRef> {
+    let name = path.canonicalize().ok().and_then(|path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    });
+
+    match name {
+        Some(name) => collect_files_named(path, name),
+        None => Vec::new(),
+    }
+}
+
+fn collect_files_named(path: PathBuf, name: String) -> Vec<FileRef> {
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Vec::new(),
+    };
+
+    if !metadata.is_dir() {
+        return vec![FileRef {
+            name,
             len: metadata.len(),
             path,
-        })
+        }];
     }
+
+    let entries = match std::fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .flat_map(|entry| {
+            let name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+            collect_files_named(entry.path(), name)
+        })
+        .collect()
 }
@@ -0,0 +1,64 @@
+use super::*;
+use anyhow::bail;
+use reqwest::StatusCode;
+use reshare_models::{Error, KEYPHRASE_HEADER};
+
+pub fn execute(args: RmArgs) -> Result<()> {
+    let server_url = load_configuration()?;
+
+    if args.file_list.is_empty() {
+        bail!("No files to remove");
+    }
+
+    let query_url = server_url.join("api/")?.join("download/")?;
+    let key_phrase = args.key_phrase.as_deref();
+    let version = args.version;
+
+    let client = http::Client::new();
+
+    for file_name in args.file_list {
+        if let Err(e) = remove_file(&client, &query_url, key_phrase, version, &file_name) {
+            println!("Err: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_file(
+    client: &http::Client,
+    query_url: &Url,
+    key_phrase: Option<&str>,
+    version: Option<u32>,
+    file_name: &str,
+) -> Result<()> {
+    let mut file_url = query_url.join(file_name)?;
+
+    if let Some(version) = version {
+        file_url
+            .query_pairs_mut()
+            .append_pair("version", &version.to_string());
+    }
+
+    let request = client.delete(file_url.clone());
+    let request = match key_phrase {
+        Some(key_phrase) => request.header(KEYPHRASE_HEADER, encode_key_phrase(key_phrase)),
+        None => request,
+    };
+
+    let resp = request
+        .send()
+        .context(format!("Failure quering {}", file_url))?;
+
+    match resp.status() {
+        status if status.is_success() => {
+            println!("{} removed", file_name);
+            Ok(())
+        }
+        StatusCode::NOT_FOUND => bail!("{} not found", file_name),
+        _ => {
+            let error: Error = resp.json()?;
+            bail!("{}", error.error_msg)
+        }
+    }
+}
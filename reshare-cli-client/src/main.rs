@@ -12,6 +12,7 @@ fn main() -> Result<()> {
         cli::Command::Put(put_args) => command::put::execute(put_args)?,
         cli::Command::Conf(config_args) => command::config::execute(config_args)?,
         cli::Command::Ls(list_args) => command::list::execute(list_args)?,
+        cli::Command::Rm(rm_args) => command::rm::execute(rm_args)?,
     }
 
     Ok(())
@@ -0,0 +1,17 @@
+//! SHA-256 digests of file contents, sent in the `Digest` header format
+//! of `reshare_models::digest`
+//!
+
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+/// Reads the whole file on a blocking thread
+pub async fn file_digest(path: PathBuf) -> std::io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(hasher.finalize().to_vec())
+    })
+    .await?
+}
@@ -1,4 +1,5 @@
 pub mod chan_connector;
+pub mod digest;
 pub mod monitored_stream;
 pub mod progress_tracker;
 
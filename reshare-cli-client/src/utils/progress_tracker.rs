@@ -4,9 +4,11 @@ use tokio::{sync::mpsc, task::JoinHandle};
 
 const CHANNEL_SIZE: usize = 40000;
 
-pub struct ProgressUpdate {
-    pub file_name: String,
-    pub bytes_transmitted: u64,
+pub enum ProgressUpdate {
+    /// More bytes of the file are transmitted
+    Transmitted { file_name: String, bytes: u64 },
+    /// Transmission of the file is resumed from the position
+    Restarted { file_name: String, position: u64 },
 }
 
 pub type ProgressReporter = mpsc::Sender<ProgressUpdate>;
@@ -51,8 +53,20 @@ impl ProgressTracker {
 
         tokio::spawn(async move {
             while let Some(progress_update) = monitor.recv().await {
-                if let Some(progress_bar) = bar_map.get(&progress_update.file_name) {
-                    progress_bar.inc(progress_update.bytes_transmitted);
+                match progress_update {
+                    ProgressUpdate::Transmitted { file_name, bytes } => {
+                        if let Some(progress_bar) = bar_map.get(&file_name) {
+                            progress_bar.inc(bytes);
+                        }
+                    }
+                    ProgressUpdate::Restarted {
+                        file_name,
+                        position,
+                    } => {
+                        if let Some(progress_bar) = bar_map.get(&file_name) {
+                            progress_bar.set_position(position);
+                        }
+                    }
                 }
             }
 
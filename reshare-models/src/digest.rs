@@ -0,0 +1,55 @@
+//! SHA-256 digests of file contents in the `Digest` header format, e.g. `SHA-256=<base64>`
+//!
+
+const SHA_256: &str = "SHA-256";
+
+/// Length of a SHA-256 digest, in bytes
+pub const SHA_256_LEN: usize = 32;
+
+/// Header value of the SHA-256 digest
+pub fn to_header(digest: &[u8]) -> String {
+    format!("{}={}", SHA_256, base64::encode(digest))
+}
+
+/// SHA-256 digest from the header value,
+/// which may list digests computed with several algorithms
+pub fn from_header(value: &str) -> Option<Vec<u8>> {
+    value
+        .split(',')
+        .filter_map(|digest| digest.trim().split_once('='))
+        .find(|(algorithm, _)| algorithm.eq_ignore_ascii_case(SHA_256))
+        .and_then(|(_, digest)| base64::decode(digest.trim()).ok())
+        .filter(|digest| digest.len() == SHA_256_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let digest: Vec<u8> = (0..SHA_256_LEN as u8).collect();
+        let header = to_header(&digest);
+
+        assert!(header.starts_with("SHA-256="));
+        assert_eq!(from_header(&header), Some(digest));
+    }
+
+    #[test]
+    fn picks_sha_256() {
+        let digest = vec![7; SHA_256_LEN];
+        let header = format!("md5=AAAA, sha-256={} ,unixsum=1", base64::encode(&digest));
+
+        assert_eq!(from_header(&header), Some(digest));
+    }
+
+    #[test]
+    fn rejects_invalid_digests() {
+        assert_eq!(from_header(""), None);
+        assert_eq!(from_header("SHA-256"), None);
+        assert_eq!(from_header("SHA-256=not base64"), None);
+        assert_eq!(from_header("md5=AAAA"), None);
+        // Too short to be a SHA-256 digest
+        assert_eq!(from_header(&to_header(&[1; 16])), None);
+    }
+}
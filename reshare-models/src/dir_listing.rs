@@ -0,0 +1,13 @@
+use crate::FileInfo;
+use serde::{Deserialize, Serialize};
+
+/// Direct children of a directory inside a shard
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirListing {
+    /// Path of the directory, empty for the root one
+    pub path: String,
+    /// Names of the subdirectories
+    pub dirs: Vec<String>,
+    /// Files with their full paths as names
+    pub files: Vec<FileInfo>,
+}
@@ -1,14 +1,78 @@
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 
+/// Version of a newly uploaded file
+pub const FIRST_VERSION: u32 = 1;
+
+/// Compression of the stored contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Zstd,
+}
+
+impl Encoding {
+    /// Value of the `Content-Encoding` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub name: String,
+    /// Size of the original contents
     pub size: u64,
     pub upload_date: DateTime<Local>,
 
+    /// Number of the revision among the files with the same name
+    #[serde(default = "first_version")]
+    pub version: u32,
+
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Local>>,
+
+    #[serde(default)]
+    pub downloads_left: Option<u32>,
+
+    /// Hex encoded SHA-256 digest of the contents
+    #[serde(default)]
+    pub digest: Option<String>,
+
+    #[serde(default)]
+    pub description: Option<String>,
+
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Compression of the contents, they're stored as is if it's not set
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+
+    /// Number of bytes the contents take in the storage
+    #[serde(default)]
+    pub stored_size: Option<u64>,
+
+    /// MIME type detected on upload
+    #[serde(default)]
+    pub content_type: Option<String>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    pub blob_id: String,
+
+    /// Fingerprint of the shard key sealing the key of the contents
+    #[serde(skip_serializing, skip_deserializing)]
+    pub key_id: Option<String>,
+
+    /// Key derived from the contents, sealed with the shard key
+    #[serde(skip_serializing, skip_deserializing)]
+    pub sealed_content_key: Option<String>,
+
+    /// Key the blob is encrypted with, sealed with the content key
     #[serde(skip_serializing, skip_deserializing)]
-    pub storage_path: std::path::PathBuf,
+    pub sealed_blob_key: Option<String>,
 }
 
 impl FileInfo {
@@ -17,10 +81,39 @@ impl FileInfo {
             name: file_name,
             size: Default::default(),
             upload_date: Local::now(),
-            storage_path: Default::default(),
+            version: FIRST_VERSION,
+            expires_at: None,
+            downloads_left: None,
+            digest: None,
+            description: None,
+            tags: Vec::new(),
+            encoding: None,
+            stored_size: None,
+            content_type: None,
+            blob_id: Default::default(),
+            key_id: None,
+            sealed_content_key: None,
+            sealed_blob_key: None,
         }
     }
 
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= Local::now())
+            .unwrap_or(false)
+    }
+
+    /// Number of bytes the contents take in the storage,
+    /// files stored before compression was introduced take their size
+    pub fn stored_size(&self) -> u64 {
+        self.stored_size.unwrap_or(self.size)
+    }
+
+    /// Whether the file has reached its download limit
+    pub fn is_exhausted(&self) -> bool {
+        self.downloads_left == Some(0)
+    }
+
     pub fn dummy() -> Self {
         Self {
             name: "dummy-file-name.jpg".to_string(),
@@ -31,11 +124,27 @@ impl FileInfo {
             )
             .unwrap()
             .into(),
-            storage_path: "/".into(),
+            version: FIRST_VERSION,
+            expires_at: None,
+            downloads_left: None,
+            digest: None,
+            description: None,
+            tags: Vec::new(),
+            encoding: None,
+            stored_size: None,
+            content_type: None,
+            blob_id: Default::default(),
+            key_id: None,
+            sealed_content_key: None,
+            sealed_blob_key: None,
         }
     }
 }
 
+fn first_version() -> u32 {
+    FIRST_VERSION
+}
+
 impl std::cmp::PartialEq for FileInfo {
     fn eq(&self, rhs: &Self) -> bool {
         self.name.eq(&rhs.name)
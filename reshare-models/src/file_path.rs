@@ -0,0 +1,133 @@
+//! Normalization of file paths, so the names of shared files
+//! are the same for all clients and safe to write on any platform
+//!
+
+use unicode_normalization::UnicodeNormalization;
+
+pub const SEPARATOR: char = '/';
+
+/// Longest name of a single file or directory, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+/// Longest path of a file, in bytes
+pub const MAX_PATH_LEN: usize = 1024;
+
+/// Characters not allowed in file names on Windows
+const FORBIDDEN_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names reserved on Windows regardless of the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Converts the path to the NFC normalized `dir/subdir/file` form,
+/// both `/` and `\` separate its components.
+/// Returns `None` if it escapes the root, doesn't name a file
+/// or any of its components isn't a valid file name
+pub fn normalize(path: &str) -> Option<String> {
+    normalize_dir(path).filter(|path| !path.is_empty())
+}
+
+/// Same as `normalize`, but an empty string stands for the root directory
+pub fn normalize_dir(path: &str) -> Option<String> {
+    let path: String = path.nfc().collect();
+    let mut segments = Vec::new();
+
+    for segment in path.split([SEPARATOR, '\\']) {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment => segments.push(normalize_name(segment)?),
+        }
+    }
+
+    Some(segments.join("/")).filter(|path| path.len() <= MAX_PATH_LEN)
+}
+
+/// Trailing dots and spaces are dropped, as Windows does
+fn normalize_name(name: &str) -> Option<&str> {
+    let name = name.trim_end_matches(['.', ' ']);
+
+    let is_valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name
+            .chars()
+            .any(|c| c.is_control() || FORBIDDEN_CHARS.contains(&c))
+        && !is_reserved(name);
+
+    Some(name).filter(|_| is_valid)
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_segments_with_slashes() {
+        assert_eq!(normalize("a/b/c.txt").as_deref(), Some("a/b/c.txt"));
+        assert_eq!(normalize("a\\b/c.txt").as_deref(), Some("a/b/c.txt"));
+        assert_eq!(normalize("/a//./b\\").as_deref(), Some("a/b"));
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        assert_eq!(normalize(".."), None);
+        assert_eq!(normalize("a/../b"), None);
+        assert_eq!(normalize("a\\..\\b"), None);
+    }
+
+    #[test]
+    fn rejects_empty_paths() {
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("/./"), None);
+        assert_eq!(normalize_dir("/./").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn drops_trailing_dots_and_spaces() {
+        assert_eq!(normalize("x.").as_deref(), Some("x"));
+        assert_eq!(normalize("dir. /x .txt ").as_deref(), Some("dir/x .txt"));
+        assert_eq!(normalize("..."), None);
+    }
+
+    #[test]
+    fn rejects_forbidden_characters() {
+        assert_eq!(normalize("a:b.txt"), None);
+        assert_eq!(normalize("what?"), None);
+        assert_eq!(normalize("tab\there"), None);
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        assert_eq!(normalize("CON"), None);
+        assert_eq!(normalize("con.txt"), None);
+        assert_eq!(normalize("dir/LPT1.tar.gz"), None);
+        assert_eq!(normalize("CONSOLE.txt").as_deref(), Some("CONSOLE.txt"));
+    }
+
+    #[test]
+    fn limits_lengths() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        assert_eq!(normalize(&name), Some(name.clone()));
+        assert_eq!(normalize(&format!("{}a", name)), None);
+
+        let path = vec!["a".repeat(100); 10].join("/");
+        assert_eq!(path.len(), 1009);
+        assert!(normalize(&path).is_some());
+        assert_eq!(normalize(&format!("{}/{}", path, "a".repeat(20))), None);
+    }
+
+    #[test]
+    fn composes_unicode() {
+        let decomposed = "caf\u{65}\u{301}.txt";
+        assert_eq!(normalize(decomposed).as_deref(), Some("caf\u{e9}.txt"));
+    }
+}
@@ -1,12 +1,30 @@
+pub mod digest;
+pub mod dir_listing;
 pub mod error;
 pub mod file_info;
+pub mod file_path;
+pub mod list_query;
+pub mod tus;
+pub mod upload;
 
+pub use dir_listing::DirListing;
 pub use error::Error;
 pub use file_info::FileInfo;
+pub use list_query::{SortKey, SortOrder};
 
 use serde::{Deserialize, Serialize};
 
+/// Header carrying the percent-encoded keyphrase of a private shard
+pub const KEYPHRASE_HEADER: &str = "X-Reshare-Key";
+
+/// Header carrying the SHA-256 digest of file contents as `SHA-256=<base64>`
+pub const DIGEST_HEADER: &str = "Digest";
+
+/// Header carrying the cursor of the next page of a listing
+pub const NEXT_CURSOR_HEADER: &str = "X-Reshare-Next-Cursor";
+
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum FileUploadStatus {
     Success(FileInfo),
     Error(Error),
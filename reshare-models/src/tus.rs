@@ -0,0 +1,25 @@
+//! Names used by resumable uploads following the tus protocol
+//!
+
+/// The only supported version of the protocol
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination";
+
+pub const TUS_RESUMABLE_HEADER: &str = "Tus-Resumable";
+pub const TUS_VERSION_HEADER: &str = "Tus-Version";
+pub const TUS_EXTENSION_HEADER: &str = "Tus-Extension";
+pub const TUS_MAX_SIZE_HEADER: &str = "Tus-Max-Size";
+pub const UPLOAD_OFFSET_HEADER: &str = "Upload-Offset";
+pub const UPLOAD_LENGTH_HEADER: &str = "Upload-Length";
+pub const UPLOAD_METADATA_HEADER: &str = "Upload-Metadata";
+
+/// Content type of the data appended to an upload
+pub const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+// Keys of the upload metadata along with the upload options
+pub use crate::upload::{DESCRIPTION_KEY, MAX_DOWNLOADS_KEY, ON_CONFLICT_KEY, TAGS_KEY, TTL_KEY};
+pub const FILENAME_KEY: &str = "filename";
+/// Content type of the file, as sent by common clients
+pub const FILETYPE_KEY: &str = "filetype";
+/// Digest of the file contents in the same format as the `Digest` header
+pub const DIGEST_KEY: &str = "digest";
@@ -0,0 +1,18 @@
+//! Names of the upload options, shared by the fields of the upload form,
+//! query parameters of raw uploads and metadata of resumable ones
+//!
+
+pub const TTL_KEY: &str = "ttl";
+pub const MAX_DOWNLOADS_KEY: &str = "max_downloads";
+pub const ON_CONFLICT_KEY: &str = "on_conflict";
+pub const DESCRIPTION_KEY: &str = "description";
+/// Comma separated tags
+pub const TAGS_KEY: &str = "tags";
+
+/// Form field carrying the digests of the uploaded files, one
+/// `<file name>:SHA-256=<base64>` per line. It may be given instead of
+/// the `Digest` headers of the parts, which not every client can set
+pub const DIGESTS_FIELD: &str = "digest";
+
+/// Form field carrying the keyphrase, which may be passed in a header instead
+pub const KEYPHRASE_FIELD: &str = "keyphrase";
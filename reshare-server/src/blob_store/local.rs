@@ -0,0 +1,332 @@
+use super::{BlobError, BlobStat, BlobStore, ByteStream, Result, StagedBlob};
+use actix_web::error::BlockingError;
+use actix_web::web::{self, Bytes, BytesMut};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use std::future::Future;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const MIN_BUF_SIZE_KB: usize = 4;
+const MAX_BUF_SIZE_KB: usize = 8192 * 2;
+const TEMP_DIR_NAME: &str = ".tmp";
+
+/// Keeps every blob as a separate file in the root directory.
+/// Blobs are written to a temporary directory first and moved
+/// into the root once complete
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    /// Opens the store, removing temporary files left by a crash
+    pub fn open(root: PathBuf) -> std::io::Result<Self> {
+        let temp_dir = root.join(TEMP_DIR_NAME);
+
+        match std::fs::remove_dir_all(&temp_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => std::fs::create_dir_all(&temp_dir)?,
+        }
+
+        Ok(Self { root })
+    }
+
+    fn blob_path(&self, id: &str) -> PathBuf {
+        self.root.join(id)
+    }
+
+    fn temp_path(&self, id: &str) -> PathBuf {
+        self.root.join(TEMP_DIR_NAME).join(id)
+    }
+}
+
+/// Removes the file unless it's committed, so that nothing is left behind
+/// when writing fails or the writing future is dropped
+struct TempFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Written file waiting in the temporary directory
+struct LocalStagedBlob {
+    temp_file: TempFile,
+    blob_path: PathBuf,
+    root: PathBuf,
+    size: u64,
+}
+
+#[async_trait(?Send)]
+impl StagedBlob for LocalStagedBlob {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn commit(mut self: Box<Self>) -> Result<()> {
+        // The blob must be complete once it's visible under its id
+        let temp_path = self.temp_file.path.clone();
+        let blob_path = self.blob_path.clone();
+        let root = self.root.clone();
+        web::block(move || {
+            std::fs::rename(temp_path, blob_path)?;
+            sync_dir(&root)
+        })
+        .await?;
+
+        self.temp_file.committed = true;
+        Ok(())
+    }
+}
+
+/// Persists the renames within the directory
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing on other platforms
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl BlobStore for LocalBlobStore {
+    async fn stage(&self, id: &str, mut data: ByteStream<'_>) -> Result<Box<dyn StagedBlob>> {
+        // A blocking write still running when the future is dropped may outlive
+        // the guard, such files are removed along with the directory on the next start
+        let temp_file = TempFile {
+            path: self.temp_path(id),
+            committed: false,
+        };
+
+        let mut f = {
+            let temp_path = temp_file.path.clone();
+            web::block(|| std::fs::File::create(temp_path)).await?
+        };
+
+        let mut bytes_written: u64 = 0;
+
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk?;
+            let chunk_size = chunk.len();
+
+            f = web::block(move || f.write_all(&chunk).map(|_| f)).await?;
+            bytes_written += chunk_size as u64;
+        }
+
+        web::block(move || f.sync_all()).await?;
+
+        Ok(Box::new(LocalStagedBlob {
+            temp_file,
+            blob_path: self.blob_path(id),
+            root: self.root.clone(),
+            size: bytes_written,
+        }))
+    }
+
+    async fn get(&self, id: &str) -> Result<ByteStream<'static>> {
+        let blob_path = self.blob_path(id);
+        let file = web::block(move || std::fs::File::open(blob_path)).await?;
+
+        Ok(Box::pin(FileStream::from(file)))
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let blob_path = self.blob_path(id);
+        web::block(move || std::fs::remove_file(blob_path)).await?;
+        Ok(())
+    }
+
+    async fn stat(&self, id: &str) -> Result<BlobStat> {
+        let blob_path = self.blob_path(id);
+        let metadata = web::block(move || std::fs::metadata(blob_path)).await?;
+
+        Ok(BlobStat {
+            size: metadata.len(),
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let root = self.root.clone();
+
+        let ids = web::block(move || {
+            let mut ids = Vec::new();
+
+            for entry in std::fs::read_dir(root)? {
+                let entry = entry?;
+
+                // The root is shared with the metadata index directory
+                if entry.file_type()?.is_file() {
+                    ids.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+
+            Ok::<_, std::io::Error>(ids)
+        })
+        .await?;
+
+        Ok(ids)
+    }
+}
+
+struct FileStream {
+    state: FileStreamState,
+    read_multiplier: usize,
+}
+
+type PendingReadFutOutput = Result<(std::fs::File, BytesMut, usize), BlockingError<std::io::Error>>;
+
+enum FileStreamState {
+    NewChunkAvailable(Option<std::fs::File>),
+    PendingRead(Pin<Box<dyn Future<Output = PendingReadFutOutput>>>),
+}
+
+impl From<std::fs::File> for FileStream {
+    fn from(file: std::fs::File) -> Self {
+        Self {
+            state: FileStreamState::NewChunkAvailable(Some(file)),
+            read_multiplier: MIN_BUF_SIZE_KB,
+        }
+    }
+}
+
+impl Stream for FileStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        const KB: usize = 1024;
+
+        let this = self.get_mut();
+
+        if let FileStreamState::NewChunkAvailable(ref mut file) = this.state {
+            let mut file = file.take().unwrap();
+
+            let buf_size = this.read_multiplier * KB;
+            let mut buf = BytesMut::with_capacity(buf_size);
+            unsafe { buf.set_len(buf_size) }
+
+            let fut = web::block(move || {
+                let bytes_read = file.read(&mut buf)?;
+                Ok::<_, std::io::Error>((file, buf, bytes_read))
+            });
+
+            this.state = FileStreamState::PendingRead(Box::pin(fut))
+        }
+
+        match this.state {
+            FileStreamState::PendingRead(ref mut fut) => match fut.as_mut().poll(cx) {
+                Poll::Ready(Ok((_, _, 0))) => Poll::Ready(None),
+                Poll::Ready(Ok((file, mut buf, bytes_read))) => {
+                    this.state = FileStreamState::NewChunkAvailable(Some(file));
+
+                    if buf.len() == bytes_read {
+                        if this.read_multiplier < MAX_BUF_SIZE_KB {
+                            this.read_multiplier *= 2;
+                        }
+                    } else {
+                        if this.read_multiplier > MIN_BUF_SIZE_KB {
+                            this.read_multiplier /= 2;
+                        }
+                        buf.truncate(bytes_read);
+                    }
+
+                    Poll::Ready(Some(Ok(buf.freeze())))
+                }
+                Poll::Ready(Err(e)) => {
+                    log::error!("Read error {}", e);
+                    Poll::Ready(Some(Err(BlobError::from(e))))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Nothing must be left under the id or in the temporary directory
+    fn assert_nothing_stored(root: &Path) {
+        assert_eq!(file_names(root), [TEMP_DIR_NAME]);
+        assert!(file_names(&root.join(TEMP_DIR_NAME)).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn failed_stream_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = LocalBlobStore::open(dir.path().to_owned()).unwrap();
+
+        let data: ByteStream<'_> = Box::pin(futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"partial")),
+            Err(BlobError::Backend("broken stream".to_owned())),
+        ]));
+        assert!(blob_store.stage("a", data).await.is_err());
+
+        assert_nothing_stored(dir.path());
+    }
+
+    #[actix_rt::test]
+    async fn dropped_write_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = LocalBlobStore::open(dir.path().to_owned()).unwrap();
+
+        // The rest of the data never arrives
+        let data: ByteStream<'_> = Box::pin(
+            futures::stream::iter(vec![Ok(Bytes::from_static(b"partial"))])
+                .chain(futures::stream::pending()),
+        );
+        let mut staging = blob_store.stage("a", data);
+        let waiting = Box::pin(actix_rt::time::delay_for(Duration::from_millis(100)));
+        let _ = futures::future::select(staging.as_mut(), waiting).await;
+
+        assert_eq!(file_names(&dir.path().join(TEMP_DIR_NAME)), ["a"]);
+        drop(staging);
+
+        assert_nothing_stored(dir.path());
+    }
+
+    #[actix_rt::test]
+    async fn discarded_blob_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = LocalBlobStore::open(dir.path().to_owned()).unwrap();
+
+        let data = Box::pin(futures::stream::iter(vec![Ok(Bytes::from_static(b"data"))]));
+        drop(blob_store.stage("a", data).await.unwrap());
+
+        assert_nothing_stored(dir.path());
+    }
+
+    #[test]
+    fn open_removes_crash_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join(TEMP_DIR_NAME);
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        std::fs::write(temp_dir.join("a"), b"partial").unwrap();
+
+        LocalBlobStore::open(dir.path().to_owned()).unwrap();
+
+        assert_nothing_stored(dir.path());
+    }
+}
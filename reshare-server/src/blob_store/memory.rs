@@ -0,0 +1,88 @@
+use super::{BlobError, BlobStat, BlobStore, ByteStream, Result, StagedBlob};
+use actix_web::web::{Bytes, BytesMut};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+type Blobs = Arc<Mutex<HashMap<String, Bytes>>>;
+
+/// Keeps blobs in memory. Contents are lost on restart,
+/// so it is meant to be used for testing only
+pub struct MemoryBlobStore {
+    blobs: Blobs,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self {
+            blobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn blobs(&self) -> MutexGuard<'_, HashMap<String, Bytes>> {
+        lock(&self.blobs)
+    }
+}
+
+fn lock(blobs: &Blobs) -> MutexGuard<'_, HashMap<String, Bytes>> {
+    blobs.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct MemoryStagedBlob {
+    blobs: Blobs,
+    id: String,
+    data: Bytes,
+}
+
+#[async_trait(?Send)]
+impl StagedBlob for MemoryStagedBlob {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    async fn commit(self: Box<Self>) -> Result<()> {
+        lock(&self.blobs).insert(self.id, self.data);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for MemoryBlobStore {
+    async fn stage(&self, id: &str, mut data: ByteStream<'_>) -> Result<Box<dyn StagedBlob>> {
+        let mut buf = BytesMut::new();
+
+        while let Some(chunk) = data.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+
+        Ok(Box::new(MemoryStagedBlob {
+            blobs: self.blobs.clone(),
+            id: id.to_owned(),
+            data: buf.freeze(),
+        }))
+    }
+
+    async fn get(&self, id: &str) -> Result<ByteStream<'static>> {
+        let blob = self.blobs().get(id).cloned().ok_or(BlobError::NotFound)?;
+
+        Ok(Box::pin(futures::stream::once(async move { Ok(blob) })))
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.blobs().remove(id).map(drop).ok_or(BlobError::NotFound)
+    }
+
+    async fn stat(&self, id: &str) -> Result<BlobStat> {
+        self.blobs()
+            .get(id)
+            .map(|blob| BlobStat {
+                size: blob.len() as u64,
+            })
+            .ok_or(BlobError::NotFound)
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.blobs().keys().cloned().collect())
+    }
+}
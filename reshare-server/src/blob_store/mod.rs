@@ -0,0 +1,258 @@
+//! Storage backends for the contents of uploaded files
+//!
+
+mod local;
+#[cfg(test)]
+mod memory;
+mod s3;
+#[cfg(test)]
+mod s3_stub;
+
+pub use local::LocalBlobStore;
+#[cfg(test)]
+pub use memory::MemoryBlobStore;
+pub use s3::{S3BlobStore, S3Config};
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::Stream;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub type Result<T, E = BlobError> = std::result::Result<T, E>;
+pub type ByteStream<'a> = Pin<Box<dyn Stream<Item = Result<Bytes>> + 'a>>;
+
+#[async_trait(?Send)]
+pub trait BlobStore: Send + Sync {
+    /// Writes `data` to be stored under `id`. The blob isn't available
+    /// until it's committed and is discarded if it's dropped before that.
+    /// Nothing is stored if the operation fails
+    async fn stage(&self, id: &str, data: ByteStream<'_>) -> Result<Box<dyn StagedBlob>>;
+
+    async fn get(&self, id: &str) -> Result<ByteStream<'static>>;
+
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    async fn stat(&self, id: &str) -> Result<BlobStat>;
+
+    /// Ids of all the stored blobs
+    async fn list(&self) -> Result<Vec<String>>;
+}
+
+/// Blob written by `BlobStore::stage`
+#[async_trait(?Send)]
+pub trait StagedBlob {
+    /// Number of bytes written
+    fn size(&self) -> u64;
+
+    /// Makes the blob available under its id
+    async fn commit(self: Box<Self>) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlobStat {
+    pub size: u64,
+}
+
+/// Backend keeping the contents of uploaded files.
+/// Only persistent backends are available, as the metadata index outlives restarts
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Files in the storage directory
+    Local,
+    S3(S3Config),
+}
+
+pub fn open(backend: &Backend, work_dir: &Path) -> std::io::Result<Arc<dyn BlobStore>> {
+    let blob_store: Arc<dyn BlobStore> = match backend {
+        Backend::Local => Arc::new(LocalBlobStore::open(work_dir.to_owned())?),
+        Backend::S3(config) => Arc::new(S3BlobStore::new(config.clone())),
+    };
+
+    Ok(blob_store)
+}
+
+#[derive(Debug, Error)]
+pub enum BlobError {
+    #[error("Requested blob doesn't exist")]
+    NotFound,
+
+    #[error("I/O failure")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+
+    #[error("Storage backend failure: {0}")]
+    Backend(String),
+
+    #[error("Data stream interrupted")]
+    Interrupted { source: Box<dyn std::error::Error> },
+}
+
+impl BlobError {
+    pub fn interrupted<E: std::error::Error + 'static>(source: E) -> Self {
+        Self::Interrupted {
+            source: Box::new(source),
+        }
+    }
+}
+
+impl From<actix_web::error::BlockingError<std::io::Error>> for BlobError {
+    fn from(err: actix_web::error::BlockingError<std::io::Error>) -> Self {
+        use actix_web::error::BlockingError;
+
+        match err {
+            BlockingError::Error(e) if e.kind() == std::io::ErrorKind::NotFound => Self::NotFound,
+            BlockingError::Error(e) => Self::Io { source: e },
+            BlockingError::Canceled => Self::Backend("Blocking operation canceled".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use s3_stub::S3Stub;
+    use std::time::Duration;
+
+    fn stream(chunks: &[&'static [u8]]) -> ByteStream<'static> {
+        let chunks: Vec<Result<Bytes>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        Box::pin(futures::stream::iter(chunks))
+    }
+
+    async fn put(blob_store: &dyn BlobStore, id: &str, data: ByteStream<'_>) -> Result<u64> {
+        let blob = blob_store.stage(id, data).await?;
+        let size = blob.size();
+        blob.commit().await?;
+
+        Ok(size)
+    }
+
+    async fn read(blob_store: &dyn BlobStore, id: &str) -> Vec<u8> {
+        let mut data = blob_store.get(id).await.unwrap();
+        let mut contents = Vec::new();
+
+        while let Some(chunk) = data.next().await {
+            contents.extend_from_slice(&chunk.unwrap());
+        }
+
+        contents
+    }
+
+    /// Behavior the rest of the server relies on, shared by all the backends
+    async fn check_conformance(blob_store: &dyn BlobStore) {
+        let data = stream(&[b"hello, ", b"world"]);
+        assert_eq!(put(blob_store, "a", data).await.unwrap(), 12);
+        assert_eq!(read(blob_store, "a").await, b"hello, world");
+        assert_eq!(blob_store.stat("a").await.unwrap().size, 12);
+
+        assert_eq!(put(blob_store, "empty", stream(&[])).await.unwrap(), 0);
+        assert_eq!(read(blob_store, "empty").await, b"");
+
+        let mut ids = blob_store.list().await.unwrap();
+        ids.sort();
+        assert_eq!(ids, ["a", "empty"]);
+
+        // Staged blobs are only available once committed
+        let staged = blob_store
+            .stage("staged", stream(&[b"data"]))
+            .await
+            .unwrap();
+        assert_eq!(staged.size(), 4);
+        assert!(matches!(
+            blob_store.stat("staged").await,
+            Err(BlobError::NotFound)
+        ));
+        drop(staged);
+        assert!(matches!(
+            blob_store.stat("staged").await,
+            Err(BlobError::NotFound)
+        ));
+
+        // Nothing is stored if the data stream fails
+        let failing: ByteStream<'_> = Box::pin(futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"partial")),
+            Err(BlobError::Backend("broken stream".to_owned())),
+        ]));
+        assert!(put(blob_store, "failed", failing).await.is_err());
+        assert!(matches!(
+            blob_store.stat("failed").await,
+            Err(BlobError::NotFound)
+        ));
+
+        blob_store.delete("a").await.unwrap();
+        assert!(matches!(
+            blob_store.get("a").await,
+            Err(BlobError::NotFound)
+        ));
+        assert!(matches!(
+            blob_store.stat("a").await,
+            Err(BlobError::NotFound)
+        ));
+        assert!(matches!(
+            blob_store.delete("a").await,
+            Err(BlobError::NotFound)
+        ));
+        assert_eq!(blob_store.list().await.unwrap(), ["empty"]);
+    }
+
+    #[actix_rt::test]
+    async fn memory_store_conforms() {
+        check_conformance(&MemoryBlobStore::new()).await;
+    }
+
+    #[actix_rt::test]
+    async fn local_store_conforms() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = LocalBlobStore::open(dir.path().to_owned()).unwrap();
+
+        check_conformance(&blob_store).await;
+    }
+
+    /// Waits for the multipart uploads aborted in the background
+    async fn wait_for_aborts(stub: &S3Stub) {
+        for _ in 0..100 {
+            if stub.pending_uploads() == 0 {
+                return;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[actix_rt::test]
+    async fn s3_store_conforms() {
+        let stub = S3Stub::start();
+
+        check_conformance(&S3BlobStore::new(stub.config())).await;
+
+        // Parts of discarded blobs don't stay in the bucket
+        wait_for_aborts(&stub).await;
+        assert_eq!(stub.pending_uploads(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn s3_failed_completion_aborts_upload() {
+        let stub = S3Stub::start();
+        let blob_store = S3BlobStore::new(stub.config());
+
+        let staged = blob_store
+            .stage(s3_stub::FAILING_KEY, stream(&[b"data"]))
+            .await
+            .unwrap();
+        assert!(staged.commit().await.is_err());
+
+        wait_for_aborts(&stub).await;
+        assert_eq!(stub.pending_uploads(), 0);
+        assert!(matches!(
+            blob_store.stat(s3_stub::FAILING_KEY).await,
+            Err(BlobError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,436 @@
+//! Blob store backed by an S3-compatible object storage.
+//! Requests are signed with AWS Signature Version 4 and objects
+//! are uploaded with multipart uploads, so the size doesn't have
+//! to be known in advance
+//!
+
+use super::{BlobError, BlobStat, BlobStore, ByteStream, Result, StagedBlob};
+use actix_web::client::{Client, ClientResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::web::{Bytes, BytesMut};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const RESPONSE_LIMIT: usize = 64 * 1024;
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Base url of the storage, e.g. `http://localhost:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Clone)]
+pub struct S3BlobStore {
+    config: S3Config,
+}
+
+impl S3BlobStore {
+    pub fn new(config: S3Config) -> Self {
+        Self { config }
+    }
+
+    fn object_path(&self, id: &str) -> String {
+        format!("/{}/{}", uri_encode(&self.config.bucket), uri_encode(id))
+    }
+
+    /// Sends a signed request. `query` must be sorted by parameter name
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Bytes,
+    ) -> Result<ClientResponse<impl Stream<Item = Result<Bytes, actix_web::error::PayloadError>>>>
+    {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, host)| host)
+            .unwrap_or(endpoint)
+            .to_owned();
+
+        let query = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", uri_encode(key), uri_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let url = if query.is_empty() {
+            format!("{}{}", endpoint, path)
+        } else {
+            format!("{}{}?{}", endpoint, path, query)
+        };
+
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = self.authorization(&method, path, &query, &host, &amz_date);
+
+        Client::builder()
+            .disable_timeout()
+            .finish()
+            .request(method, url)
+            .header(header::HOST, host)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .header(header::AUTHORIZATION, authorization)
+            .send_body(body)
+            .await
+            .map_err(|e| BlobError::Backend(e.to_string()))
+    }
+
+    fn authorization(
+        &self,
+        method: &Method,
+        path: &str,
+        query: &str,
+        host: &str,
+        amz_date: &str,
+    ) -> String {
+        const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, UNSIGNED_PAYLOAD, amz_date, SIGNED_HEADERS, UNSIGNED_PAYLOAD
+        );
+
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let secret = format!("AWS4{}", self.config.secret_key);
+        let signing_key = [date, &self.config.region, "s3", "aws4_request"]
+            .iter()
+            .fold(secret.into_bytes(), |key, part| hmac_sha256(&key, part));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key,
+            scope,
+            SIGNED_HEADERS,
+            hex::encode(hmac_sha256(&signing_key, &string_to_sign))
+        )
+    }
+
+    async fn abort_upload(&self, path: &str, upload_id: &str) {
+        let aborted = self
+            .send(
+                Method::DELETE,
+                path,
+                &[("uploadId", upload_id)],
+                Bytes::new(),
+            )
+            .await;
+
+        if let Err(e) = aborted {
+            log::error!("Failed to abort upload {}: {}", upload_id, e);
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        path: &str,
+        upload_id: &str,
+        mut data: ByteStream<'_>,
+    ) -> Result<(u64, Vec<String>)> {
+        let mut etags = Vec::new();
+        let mut bytes_written: u64 = 0;
+        let mut buf = BytesMut::with_capacity(PART_SIZE);
+        let mut finished = false;
+
+        while !finished {
+            match data.next().await {
+                Some(chunk) => buf.extend_from_slice(&chunk?),
+                None => finished = true,
+            }
+
+            // An object always has at least one part, even if it is empty
+            if buf.len() >= PART_SIZE || (finished && (!buf.is_empty() || etags.is_empty())) {
+                let part = buf.split().freeze();
+                let part_number = (etags.len() + 1).to_string();
+                bytes_written += part.len() as u64;
+
+                let resp = self
+                    .send(
+                        Method::PUT,
+                        path,
+                        &[("partNumber", &part_number), ("uploadId", upload_id)],
+                        part,
+                    )
+                    .await?;
+
+                let resp = ensure_success(resp).await?;
+                let etag = resp
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .ok_or_else(|| BlobError::Backend("Part ETag is missing".to_owned()))?;
+
+                etags.push(etag.to_owned());
+            }
+        }
+
+        Ok((bytes_written, etags))
+    }
+}
+
+/// Multipart upload with all the parts uploaded, the object
+/// doesn't exist until the upload is completed
+struct S3StagedBlob {
+    abort_guard: AbortGuard,
+    etags: Vec<String>,
+    size: u64,
+}
+
+#[async_trait(?Send)]
+impl StagedBlob for S3StagedBlob {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn commit(mut self: Box<Self>) -> Result<()> {
+        let parts: String = self
+            .etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    i + 1,
+                    etag
+                )
+            })
+            .collect();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+
+        let guard = &self.abort_guard;
+        let resp = guard
+            .store
+            .send(
+                Method::POST,
+                &guard.path,
+                &[("uploadId", &guard.upload_id)],
+                body.into(),
+            )
+            .await?;
+        let body = read_body(ensure_success(resp).await?).await?;
+
+        // Completion may fail after the response status was already sent
+        if xml_value(&body, "Code").is_some() {
+            return Err(BlobError::Backend(body));
+        }
+
+        // The parts are only aborted if the upload wasn't completed
+        self.abort_guard.armed = false;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for S3BlobStore {
+    async fn stage(&self, id: &str, data: ByteStream<'_>) -> Result<Box<dyn StagedBlob>> {
+        let path = self.object_path(id);
+
+        let resp = self
+            .send(Method::POST, &path, &[("uploads", "")], Bytes::new())
+            .await?;
+        let body = read_body(ensure_success(resp).await?).await?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or_else(|| BlobError::Backend("Upload id is missing".to_owned()))?;
+
+        // Uploaded parts are kept by the storage until the upload is aborted
+        let mut abort_guard = AbortGuard {
+            store: self.clone(),
+            path: path.clone(),
+            upload_id: upload_id.clone(),
+            armed: true,
+        };
+
+        let (bytes_written, etags) = match self.upload_parts(&path, &upload_id, data).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                abort_guard.armed = false;
+                self.abort_upload(&path, &upload_id).await;
+                return Err(e);
+            }
+        };
+
+        Ok(Box::new(S3StagedBlob {
+            abort_guard,
+            etags,
+            size: bytes_written,
+        }))
+    }
+
+    async fn get(&self, id: &str) -> Result<ByteStream<'static>> {
+        let resp = self
+            .send(Method::GET, &self.object_path(id), &[], Bytes::new())
+            .await?;
+        let resp = ensure_success(resp).await?;
+
+        Ok(Box::pin(
+            resp.map_err(|e| BlobError::Backend(e.to_string())),
+        ))
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        // S3 doesn't report missing objects on deletion
+        self.stat(id).await?;
+
+        let resp = self
+            .send(Method::DELETE, &self.object_path(id), &[], Bytes::new())
+            .await?;
+        ensure_success(resp).await?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: &str) -> Result<BlobStat> {
+        let resp = self
+            .send(Method::HEAD, &self.object_path(id), &[], Bytes::new())
+            .await?;
+        let resp = ensure_success(resp).await?;
+
+        let size = resp
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| BlobError::Backend("Object size is missing".to_owned()))?;
+
+        Ok(BlobStat { size })
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let path = format!("/{}", uri_encode(&self.config.bucket));
+        let mut ids = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        // Objects are listed in pages of up to 1000 keys
+        loop {
+            let mut query = Vec::new();
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            query.push(("list-type", "2"));
+
+            let resp = self.send(Method::GET, &path, &query, Bytes::new()).await?;
+            let body = read_body(ensure_success(resp).await?).await?;
+
+            ids.extend(xml_values(&body, "Key"));
+
+            match xml_value(&body, "NextContinuationToken") {
+                Some(token) => continuation_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(ids)
+    }
+}
+
+/// Aborts the multipart upload if the uploading future or the staged blob
+/// is dropped, e.g. when the client disconnects
+struct AbortGuard {
+    store: S3BlobStore,
+    path: String,
+    upload_id: String,
+    armed: bool,
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let store = self.store.clone();
+        let path = std::mem::take(&mut self.path);
+        let upload_id = std::mem::take(&mut self.upload_id);
+
+        actix_web::rt::spawn(async move {
+            store.abort_upload(&path, &upload_id).await;
+        });
+    }
+}
+
+async fn ensure_success<S>(resp: ClientResponse<S>) -> Result<ClientResponse<S>>
+where
+    S: Stream<Item = Result<Bytes, actix_web::error::PayloadError>> + Unpin,
+{
+    match resp.status() {
+        status if status.is_success() => Ok(resp),
+        StatusCode::NOT_FOUND => Err(BlobError::NotFound),
+        status => {
+            let body = read_body(resp).await.unwrap_or_default();
+            Err(BlobError::Backend(format!("{}: {}", status, body)))
+        }
+    }
+}
+
+async fn read_body<S>(mut resp: ClientResponse<S>) -> Result<String>
+where
+    S: Stream<Item = Result<Bytes, actix_web::error::PayloadError>> + Unpin,
+{
+    let body = resp
+        .body()
+        .limit(RESPONSE_LIMIT)
+        .await
+        .map_err(|e| BlobError::Backend(e.to_string()))?;
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+
+    Some(xml[start..end].to_owned())
+}
+
+pub(super) fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut rest = xml;
+
+    while let Some(value) = xml_value(rest, tag) {
+        let end = rest.find(&format!("</{}>", tag)).unwrap_or(rest.len()) + tag.len() + 3;
+        values.push(value);
+        rest = &rest[end..];
+    }
+
+    values
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything except unreserved characters as required by SigV4
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,229 @@
+//! In-process stand-in for an S3-compatible storage, implementing the part
+//! of the API used by `S3BlobStore`. Requests aren't authenticated
+//!
+
+use super::s3::xml_values;
+use super::S3Config;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{test, App, HttpResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Key of the objects which multipart uploads fail to complete
+pub const FAILING_KEY: &str = "failing";
+
+const BUCKET: &str = "bucket";
+// Listings are split into pages of a single key to go through continuation tokens
+const LIST_PAGE_SIZE: usize = 1;
+
+type Query = web::Query<HashMap<String, String>>;
+
+#[derive(Default)]
+struct Bucket {
+    objects: BTreeMap<String, Bytes>,
+    /// Key of the object and parts by number of every multipart upload
+    uploads: HashMap<String, (String, BTreeMap<u32, Bytes>)>,
+    last_upload_id: u64,
+}
+
+type SharedBucket = web::Data<Mutex<Bucket>>;
+
+pub struct S3Stub {
+    server: test::TestServer,
+    bucket: SharedBucket,
+}
+
+impl S3Stub {
+    pub fn start() -> Self {
+        let bucket = SharedBucket::new(Mutex::new(Bucket::default()));
+
+        let server = test::start({
+            let bucket = bucket.clone();
+            move || {
+                App::new()
+                    .app_data(bucket.clone())
+                    .service(web::resource("/{bucket}").route(web::get().to(list)))
+                    .service(
+                        web::resource("/{bucket}/{key}")
+                            .route(web::post().to(post))
+                            .route(web::put().to(upload_part))
+                            .route(web::get().to(get))
+                            .route(web::head().to(get))
+                            .route(web::delete().to(delete)),
+                    )
+            }
+        });
+
+        Self { server, bucket }
+    }
+
+    pub fn config(&self) -> S3Config {
+        S3Config {
+            endpoint: format!("http://{}", self.server.addr()),
+            bucket: BUCKET.to_owned(),
+            region: "us-east-1".to_owned(),
+            access_key: "access".to_owned(),
+            secret_key: "secret".to_owned(),
+        }
+    }
+
+    /// Number of multipart uploads neither completed nor aborted
+    pub fn pending_uploads(&self) -> usize {
+        lock(&self.bucket).uploads.len()
+    }
+}
+
+fn lock(bucket: &SharedBucket) -> MutexGuard<'_, Bucket> {
+    bucket.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn xml(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(body)
+}
+
+fn part_etag(part_number: u32) -> String {
+    format!("\"{}\"", part_number)
+}
+
+/// Creates and completes multipart uploads
+async fn post(
+    bucket: SharedBucket,
+    web::Path((_, key)): web::Path<(String, String)>,
+    web::Query(query): Query,
+    body: Bytes,
+) -> HttpResponse {
+    let mut bucket = lock(&bucket);
+
+    if query.contains_key("uploads") {
+        bucket.last_upload_id += 1;
+        let upload_id = bucket.last_upload_id.to_string();
+        bucket
+            .uploads
+            .insert(upload_id.clone(), (key, BTreeMap::new()));
+
+        return xml(format!(
+            "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+            upload_id
+        ));
+    }
+
+    let upload_id = match query.get("uploadId") {
+        Some(upload_id) => upload_id,
+        None => return HttpResponse::BadRequest().finish(),
+    };
+
+    let parts = match bucket.uploads.get(upload_id) {
+        Some((upload_key, _)) if upload_key.as_str() == FAILING_KEY => {
+            // S3 may report the failure after the status of the response was sent
+            return xml("<Error><Code>InternalError</Code></Error>".to_owned());
+        }
+        Some((_, parts)) => parts,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let etags = xml_values(&String::from_utf8_lossy(&body), "ETag");
+    if !etags.into_iter().eq(parts.keys().copied().map(part_etag)) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let (key, parts) = bucket.uploads.remove(upload_id).unwrap();
+    let mut object = BytesMut::new();
+    for part in parts.values() {
+        object.extend_from_slice(part);
+    }
+    bucket.objects.insert(key, object.freeze());
+
+    xml("<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_owned())
+}
+
+async fn upload_part(bucket: SharedBucket, web::Query(query): Query, body: Bytes) -> HttpResponse {
+    let part_number = match query
+        .get("partNumber")
+        .and_then(|number| number.parse().ok())
+    {
+        Some(part_number) => part_number,
+        None => return HttpResponse::BadRequest().finish(),
+    };
+
+    let mut bucket = lock(&bucket);
+    let upload = query
+        .get("uploadId")
+        .and_then(|upload_id| bucket.uploads.get_mut(upload_id));
+
+    match upload {
+        Some((_, parts)) => {
+            parts.insert(part_number, body);
+            HttpResponse::Ok()
+                .header(header::ETAG, part_etag(part_number))
+                .finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Contents of the object, only the headers are sent in response to HEAD
+async fn get(
+    bucket: SharedBucket,
+    web::Path((_, key)): web::Path<(String, String)>,
+) -> HttpResponse {
+    match lock(&bucket).objects.get(&key) {
+        Some(object) => HttpResponse::Ok().body(object.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Deletes objects and aborts multipart uploads, missing ones aren't reported
+async fn delete(
+    bucket: SharedBucket,
+    web::Path((_, key)): web::Path<(String, String)>,
+    web::Query(query): Query,
+) -> HttpResponse {
+    let mut bucket = lock(&bucket);
+
+    match query.get("uploadId") {
+        Some(upload_id) => {
+            bucket.uploads.remove(upload_id);
+        }
+        None => {
+            bucket.objects.remove(&key);
+        }
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+/// ListObjectsV2, the continuation token is the number of keys already listed
+async fn list(bucket: SharedBucket, web::Query(query): Query) -> HttpResponse {
+    if query.get("list-type").map(String::as_str) != Some("2") {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let listed = query
+        .get("continuation-token")
+        .and_then(|token| token.parse().ok())
+        .unwrap_or(0);
+
+    let bucket = lock(&bucket);
+    let contents: String = bucket
+        .objects
+        .keys()
+        .skip(listed)
+        .take(LIST_PAGE_SIZE)
+        .map(|key| format!("<Contents><Key>{}</Key></Contents>", key))
+        .collect();
+
+    let next_token = match listed + LIST_PAGE_SIZE {
+        next if next < bucket.objects.len() => {
+            format!("<NextContinuationToken>{}</NextContinuationToken>", next)
+        }
+        _ => String::new(),
+    };
+
+    xml(format!(
+        "<ListBucketResult>{}{}</ListBucketResult>",
+        contents, next_token
+    ))
+}
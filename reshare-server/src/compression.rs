@@ -0,0 +1,261 @@
+//! Transparent zstd compression of the stored contents.
+//! Files are compressed on upload unless their contents are likely
+//! compressed already, and decompressed on download
+//!
+
+use crate::blob_store::{BlobError, ByteStream};
+use actix_web::http::{header, HeaderMap};
+use actix_web::web::{self, Bytes};
+use futures::StreamExt;
+use reshare_models::file_info::Encoding;
+use std::io::Write;
+
+/// Extensions of the formats which are compressed by themselves
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "png", "pptx", "rar",
+    "tbz2", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Content types of the formats which are compressed by themselves,
+/// prefixes ending with `/` match whole groups of types
+const COMPRESSED_CONTENT_TYPES: &[&str] = &[
+    "image/",
+    "audio/",
+    "video/",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-rar-compressed",
+    "application/x-xz",
+];
+
+/// Compression applied to the uploaded files
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compression {
+    /// Level of zstd compression, files are stored as is if it's not set
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    /// Level the file should be compressed with, if any.
+    /// The declared content type is only a hint, as clients often send a generic one
+    pub fn level_for(&self, file_name: &str, content_type: Option<&str>) -> Option<i32> {
+        let extension = file_name
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        let is_compressed_extension = extension
+            .map(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+            .unwrap_or(false);
+
+        let is_compressed_type = content_type
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+            .map(|content_type| {
+                COMPRESSED_CONTENT_TYPES.iter().any(|compressed| {
+                    if compressed.ends_with('/') {
+                        content_type.starts_with(compressed)
+                    } else {
+                        content_type.split(';').next() == Some(compressed)
+                    }
+                })
+            })
+            .unwrap_or(false);
+
+        self.level
+            .filter(|_| !is_compressed_extension && !is_compressed_type)
+    }
+}
+
+/// Compresses the data with zstd as it's streamed
+pub fn compress(data: ByteStream<'_>, level: i32) -> Result<ByteStream<'_>, BlobError> {
+    let encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+
+    Ok(run(data, encoder))
+}
+
+/// Decompresses the data stored with the given encoding as it's streamed
+pub fn decompress(data: ByteStream<'_>, encoding: Encoding) -> Result<ByteStream<'_>, BlobError> {
+    match encoding {
+        Encoding::Zstd => {
+            let decoder = zstd::stream::write::Decoder::new(Vec::new())?;
+
+            Ok(run(data, decoder))
+        }
+    }
+}
+
+/// Whether the `Accept-Encoding` header allows the response to be sent with the encoding
+pub fn is_accepted(headers: &HeaderMap, encoding: Encoding) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+
+            // Zero quality stands for "not acceptable"
+            let is_refused = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .any(|quality| quality.parse::<f32>().map(|q| q <= 0.0).unwrap_or(false));
+
+            name.eq_ignore_ascii_case(encoding.as_str()) && !is_refused
+        })
+}
+
+/// Streaming encoder or decoder writing its output to a buffer
+trait Codec: Send + 'static {
+    /// Processes the chunk and returns the output produced so far
+    fn write(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Returns the rest of the output once the data ends
+    fn finish(self) -> std::io::Result<Vec<u8>>;
+}
+
+impl Codec for zstd::stream::write::Encoder<'static, Vec<u8>> {
+    fn write(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.write_all(chunk)?;
+        Ok(std::mem::take(self.get_mut()))
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        // Writes the end of the frame
+        zstd::stream::write::Encoder::finish(self)
+    }
+}
+
+impl Codec for zstd::stream::write::Decoder<'static, Vec<u8>> {
+    fn write(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.write_all(chunk)?;
+        self.flush()?;
+        Ok(std::mem::take(self.get_mut()))
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        Ok(self.into_inner())
+    }
+}
+
+/// Runs the codec over the data. Every chunk is processed on the blocking
+/// thread pool, as compression would otherwise stall the other requests
+fn run<C: Codec>(data: ByteStream<'_>, codec: C) -> ByteStream<'_> {
+    Box::pin(futures::stream::try_unfold(
+        (data, Some(codec)),
+        |(mut data, codec)| async move {
+            // Taken once the data ends
+            let mut codec = match codec {
+                Some(codec) => codec,
+                None => return Ok(None),
+            };
+
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk?;
+                let (returned, output) =
+                    web::block(move || codec.write(&chunk).map(|output| (codec, output))).await?;
+                codec = returned;
+
+                // Small chunks may not produce any output yet
+                if !output.is_empty() {
+                    return Ok(Some((Bytes::from(output), (data, Some(codec)))));
+                }
+            }
+
+            let output = web::block(move || codec.finish()).await?;
+            if output.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some((Bytes::from(output), (data, None))))
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn stream(data: &[u8], chunk_size: usize) -> ByteStream<'static> {
+        let chunks: Vec<_> = data
+            .chunks(chunk_size)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+
+        Box::pin(futures::stream::iter(chunks))
+    }
+
+    async fn collect(mut data: ByteStream<'_>) -> Result<Vec<u8>, BlobError> {
+        let mut collected = Vec::new();
+
+        while let Some(chunk) = data.next().await {
+            collected.extend_from_slice(&chunk?);
+        }
+
+        Ok(collected)
+    }
+
+    fn headers(accept_encoding: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in accept_encoding {
+            headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+
+        headers
+    }
+
+    #[actix_rt::test]
+    async fn round_trips() {
+        for &len in &[0, 1, 1000, 100_000, 1_000_000] {
+            let data = contents(len);
+
+            let compressed = collect(compress(stream(&data, 4096), 3).unwrap())
+                .await
+                .unwrap();
+            if len >= 1000 {
+                assert!(compressed.len() < data.len());
+            }
+
+            // Decompressed in chunks which don't match the compressed ones
+            let decompressed = collect(decompress(stream(&compressed, 7), Encoding::Zstd).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rejects_corrupted_data() {
+        let compressed = collect(compress(stream(&contents(1000), 4096), 3).unwrap())
+            .await
+            .unwrap();
+
+        let result =
+            collect(decompress(stream(&compressed[1..], 4096), Encoding::Zstd).unwrap()).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn accepts_listed_encodings() {
+        let is_zstd_accepted =
+            |values: &[&'static str]| is_accepted(&headers(values), Encoding::Zstd);
+
+        assert!(is_zstd_accepted(&["zstd"]));
+        assert!(is_zstd_accepted(&["ZSTD"]));
+        assert!(is_zstd_accepted(&["gzip, Zstd;q=0.5"]));
+        assert!(is_zstd_accepted(&["gzip", "zstd"]));
+        assert!(is_zstd_accepted(&["zstd; q=1"]));
+
+        assert!(!is_zstd_accepted(&[]));
+        assert!(!is_zstd_accepted(&["gzip, br"]));
+        assert!(!is_zstd_accepted(&["zstd;q=0"]));
+        assert!(!is_zstd_accepted(&["gzip, zstd; q=0.0"]));
+        assert!(!is_zstd_accepted(&["zstdx"]));
+    }
+}
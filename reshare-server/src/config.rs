@@ -0,0 +1,379 @@
+//! Server settings gathered from the config file, environment and command line.
+//! Command-line options take precedence over environment variables,
+//! which in turn take precedence over the config file
+//!
+
+use crate::blob_store::{Backend, S3Config};
+use crate::compression::Compression;
+use crate::encryption::BlobKey;
+use crate::quota::Quotas;
+use crate::uploader::UploadLimits;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::StructOpt;
+use thiserror::Error;
+
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_STORAGE_DIR_NAME: &str = "reshare_files";
+const DEFAULT_STATIC_DIR: &str = "./web_page";
+const DEFAULT_LOG_LEVEL: &str = "reshare_server=debug,actix_web=info";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+const CONFIG_VAR: &str = "RESHARE_CONFIG";
+// Set by hosting platforms, binds all the interfaces
+const PORT_VAR: &str = "PORT";
+const LISTEN_ADDR_VAR: &str = "RESHARE_LISTEN_ADDR";
+const STORAGE_DIR_VAR: &str = "RESHARE_STORAGE_DIR";
+const STATIC_DIR_VAR: &str = "RESHARE_STATIC_DIR";
+const LOG_LEVEL_VAR: &str = "RUST_LOG";
+const LEGACY_ROUTES_VAR: &str = "RESHARE_LEGACY_ROUTES";
+const STARTUP_CHECK_VAR: &str = "RESHARE_STARTUP_CHECK";
+const MAX_TOTAL_SIZE_VAR: &str = "RESHARE_MAX_TOTAL_SIZE";
+const MAX_SHARD_SIZE_VAR: &str = "RESHARE_MAX_SHARD_SIZE";
+const MAX_FILE_SIZE_VAR: &str = "RESHARE_MAX_FILE_SIZE";
+const MAX_REQUEST_SIZE_VAR: &str = "RESHARE_MAX_REQUEST_SIZE";
+const DEFAULT_TTL_VAR: &str = "RESHARE_DEFAULT_TTL";
+const DEFAULT_MAX_DOWNLOADS_VAR: &str = "RESHARE_DEFAULT_MAX_DOWNLOADS";
+const COMPRESSION_LEVEL_VAR: &str = "RESHARE_COMPRESSION_LEVEL";
+const ENCRYPTION_KEY_VAR: &str = "RESHARE_ENCRYPTION_KEY";
+const BLOB_STORE_VAR: &str = "RESHARE_BLOB_STORE";
+const S3_ENDPOINT_VAR: &str = "RESHARE_S3_ENDPOINT";
+const S3_BUCKET_VAR: &str = "RESHARE_S3_BUCKET";
+const S3_REGION_VAR: &str = "RESHARE_S3_REGION";
+const S3_ACCESS_KEY_VAR: &str = "RESHARE_S3_ACCESS_KEY";
+const S3_SECRET_KEY_VAR: &str = "RESHARE_S3_SECRET_KEY";
+
+pub type Result<T, E = ConfigError> = std::result::Result<T, E>;
+
+pub fn parse_args() -> Args {
+    Args::from_args()
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "reshare-server", about = "Reshare file sharing server")]
+pub struct Args {
+    #[structopt(short, long)]
+    /// Path to a TOML config file, also read from RESHARE_CONFIG
+    pub config: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Address to listen on, e.g. "127.0.0.1:8080"
+    pub listen_addr: Option<String>,
+
+    #[structopt(long)]
+    /// Directory keeping the uploaded files and their metadata
+    pub storage_dir: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Directory with the web client assets
+    pub static_dir: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Log filter in the `env_logger` format, e.g. "info"
+    pub log_level: Option<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Checks that the stored files match their metadata and exits.
+    /// Blobs left by interrupted uploads are deleted
+    Fsck {
+        #[structopt(long)]
+        /// Only report the problems
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_addr: Option<String>,
+    pub storage_dir: Option<PathBuf>,
+    pub static_dir: Option<PathBuf>,
+    pub log_level: Option<String>,
+    /// Enables routes taking keyphrases in the path
+    pub legacy_routes: Option<bool>,
+    /// Runs the consistency check on startup
+    pub startup_check: Option<bool>,
+    pub limits: Limits,
+    pub retention: Retention,
+    /// Level of zstd compression of the stored files, disabled if not set
+    pub compression_level: Option<i32>,
+    /// Hex encoded 256-bit key of the public shard contents,
+    /// which are stored unencrypted if it's not set
+    pub encryption_key: Option<String>,
+    pub blob_store: BlobStoreConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobStoreConfig {
+    /// Either "local" or "s3", files are kept in the storage directory by default
+    pub backend: Option<String>,
+    pub s3: S3Section,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Section {
+    /// Base url of the storage, e.g. `http://localhost:9000`
+    pub endpoint: Option<String>,
+    pub bucket: Option<String>,
+    pub region: Option<String>,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum number of bytes stored on the server
+    pub max_total_size: Option<u64>,
+    /// Maximum number of bytes stored in a single private shard
+    pub max_shard_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+    /// Maximum size of an upload request, which may carry several files
+    pub max_request_size: Option<u64>,
+}
+
+/// Defaults for uploads that don't limit the lifetime of their files
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Time-to-live in seconds
+    pub default_ttl: Option<u32>,
+    pub default_max_downloads: Option<u32>,
+}
+
+impl Retention {
+    /// Time-to-live of a file uploaded with the given one
+    pub fn ttl(&self, requested: Option<chrono::Duration>) -> Option<chrono::Duration> {
+        requested.or_else(|| {
+            self.default_ttl
+                .map(|secs| chrono::Duration::seconds(secs.into()))
+        })
+    }
+
+    /// Download limit of a file uploaded with the given one
+    pub fn max_downloads(&self, requested: Option<u32>) -> Option<u32> {
+        requested.or(self.default_max_downloads)
+    }
+}
+
+/// Settings the server runs with
+#[derive(Debug)]
+pub struct Settings {
+    pub listen_addr: String,
+    pub storage_dir: PathBuf,
+    pub static_dir: PathBuf,
+    pub log_level: String,
+    pub legacy_routes: bool,
+    pub startup_check: bool,
+    pub quotas: Quotas,
+    pub upload_limits: UploadLimits,
+    pub retention: Retention,
+    pub compression: Compression,
+    pub master_key: Option<BlobKey>,
+    pub blob_store: Backend,
+}
+
+impl Settings {
+    pub fn load(args: &Args) -> Result<Self> {
+        let config_path = args
+            .config
+            .clone()
+            .or_else(|| std::env::var_os(CONFIG_VAR).map(PathBuf::from));
+
+        let mut config = match config_path {
+            Some(path) => Config::read(&path)?,
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+
+        let listen_addr = args
+            .listen_addr
+            .clone()
+            .or(config.listen_addr)
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_owned());
+
+        let storage_dir = match args.storage_dir.clone().or(config.storage_dir) {
+            Some(dir) => dir,
+            None => dirs_next::home_dir()
+                .unwrap_or_else(|| PathBuf::from("/"))
+                .join(DEFAULT_STORAGE_DIR_NAME),
+        };
+
+        if let Some(level) = config.compression_level {
+            if !zstd::compression_level_range().contains(&level) {
+                return Err(ConfigError::InvalidCompressionLevel { level });
+            }
+        }
+
+        let master_key = config
+            .encryption_key
+            .map(|key| BlobKey::from_hex(&key).ok_or(ConfigError::InvalidEncryptionKey))
+            .transpose()?;
+
+        let blob_store = config.blob_store.backend()?;
+
+        Ok(Self {
+            listen_addr,
+            storage_dir,
+            static_dir: args
+                .static_dir
+                .clone()
+                .or(config.static_dir)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_STATIC_DIR)),
+            log_level: args
+                .log_level
+                .clone()
+                .or(config.log_level)
+                .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_owned()),
+            legacy_routes: config.legacy_routes.unwrap_or(false),
+            startup_check: config.startup_check.unwrap_or(true),
+            quotas: Quotas {
+                max_total_size: config.limits.max_total_size,
+                max_shard_size: config.limits.max_shard_size,
+            },
+            upload_limits: UploadLimits {
+                max_file_size: config.limits.max_file_size,
+                max_request_size: config.limits.max_request_size,
+            },
+            retention: config.retention,
+            compression: Compression {
+                level: config.compression_level,
+            },
+            master_key,
+            blob_store,
+        })
+    }
+}
+
+impl Config {
+    fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(port) = env_var::<u16>(PORT_VAR)? {
+            self.listen_addr = Some(format!("0.0.0.0:{}", port));
+        }
+
+        override_with_env(&mut self.listen_addr, LISTEN_ADDR_VAR)?;
+        override_with_env(&mut self.storage_dir, STORAGE_DIR_VAR)?;
+        override_with_env(&mut self.static_dir, STATIC_DIR_VAR)?;
+        override_with_env(&mut self.log_level, LOG_LEVEL_VAR)?;
+        override_with_env(&mut self.startup_check, STARTUP_CHECK_VAR)?;
+        override_with_env(&mut self.limits.max_total_size, MAX_TOTAL_SIZE_VAR)?;
+        override_with_env(&mut self.limits.max_shard_size, MAX_SHARD_SIZE_VAR)?;
+        override_with_env(&mut self.limits.max_file_size, MAX_FILE_SIZE_VAR)?;
+        override_with_env(&mut self.limits.max_request_size, MAX_REQUEST_SIZE_VAR)?;
+        override_with_env(&mut self.retention.default_ttl, DEFAULT_TTL_VAR)?;
+        override_with_env(
+            &mut self.retention.default_max_downloads,
+            DEFAULT_MAX_DOWNLOADS_VAR,
+        )?;
+        override_with_env(&mut self.compression_level, COMPRESSION_LEVEL_VAR)?;
+        override_with_env(&mut self.encryption_key, ENCRYPTION_KEY_VAR)?;
+        override_with_env(&mut self.blob_store.backend, BLOB_STORE_VAR)?;
+        override_with_env(&mut self.blob_store.s3.endpoint, S3_ENDPOINT_VAR)?;
+        override_with_env(&mut self.blob_store.s3.bucket, S3_BUCKET_VAR)?;
+        override_with_env(&mut self.blob_store.s3.region, S3_REGION_VAR)?;
+        override_with_env(&mut self.blob_store.s3.access_key, S3_ACCESS_KEY_VAR)?;
+        override_with_env(&mut self.blob_store.s3.secret_key, S3_SECRET_KEY_VAR)?;
+
+        if let Ok(val) = std::env::var(LEGACY_ROUTES_VAR) {
+            self.legacy_routes = Some(val == "1" || val.eq_ignore_ascii_case("true"));
+        }
+
+        Ok(())
+    }
+}
+
+impl BlobStoreConfig {
+    fn backend(self) -> Result<Backend> {
+        fn required(value: Option<String>, name: &'static str) -> Result<String> {
+            value.ok_or(ConfigError::MissingS3Setting { name })
+        }
+
+        match self.backend.as_deref() {
+            None | Some("local") => Ok(Backend::Local),
+            Some("s3") => {
+                let s3 = self.s3;
+
+                Ok(Backend::S3(S3Config {
+                    endpoint: required(s3.endpoint, "endpoint")?,
+                    bucket: required(s3.bucket, "bucket")?,
+                    region: s3.region.unwrap_or_else(|| DEFAULT_S3_REGION.to_owned()),
+                    access_key: required(s3.access_key, "access_key")?,
+                    secret_key: required(s3.secret_key, "secret_key")?,
+                }))
+            }
+            Some(other) => Err(ConfigError::UnknownBlobStore {
+                backend: other.to_owned(),
+            }),
+        }
+    }
+}
+
+fn override_with_env<T: FromStr>(value: &mut Option<T>, name: &'static str) -> Result<()> {
+    if let Some(env_value) = env_var(name)? {
+        *value = Some(env_value);
+    }
+
+    Ok(())
+}
+
+fn env_var<T: FromStr>(name: &'static str) -> Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(val) => val
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidVar { name }),
+        Err(_) => Ok(None),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Invalid value of {name}")]
+    InvalidVar { name: &'static str },
+
+    #[error("Unsupported compression level {level}")]
+    InvalidCompressionLevel { level: i32 },
+
+    #[error("Encryption key must be 64 hex digits")]
+    InvalidEncryptionKey,
+
+    #[error("Unknown blob store: {backend}")]
+    UnknownBlobStore { backend: String },
+
+    #[error("blob_store.s3.{name} must be set to use S3 blob store")]
+    MissingS3Setting { name: &'static str },
+}
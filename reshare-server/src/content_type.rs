@@ -0,0 +1,38 @@
+//! Detection of the content types of uploaded files
+//!
+
+/// Number of leading bytes of the contents the type is detected by
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+/// Content type recognized by the leading bytes of the contents,
+/// the extension of the file is used for the formats without a signature, e.g. text
+pub fn detect(head: &[u8], file_name: &str) -> Option<String> {
+    infer::get(head)
+        .map(|kind| kind.mime_type().to_owned())
+        .or_else(|| {
+            mime_guess::from_path(file_name)
+                .first_raw()
+                .map(str::to_owned)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_HEAD: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn detects_by_contents() {
+        assert_eq!(detect(PNG_HEAD, "image.txt").as_deref(), Some("image/png"));
+    }
+
+    #[test]
+    fn falls_back_to_extension() {
+        assert_eq!(
+            detect(b"# Notes\n\nSome text", "notes.md").as_deref(),
+            Some("text/markdown")
+        );
+        assert_eq!(detect(b"Some text", "notes"), None);
+    }
+}
@@ -0,0 +1,38 @@
+//! Digests of file contents in the `Digest` header format, e.g. `SHA-256=<base64>`.
+//! The stored digests are hex encoded
+//!
+
+use actix_web::http::HeaderMap;
+use reshare_models::{digest, DIGEST_HEADER};
+use thiserror::Error;
+
+/// Header value of the hex encoded SHA-256 digest
+pub fn to_header(hex_digest: &str) -> Option<String> {
+    let digest = hex::decode(hex_digest).ok()?;
+    Some(digest::to_header(&digest))
+}
+
+/// Hex encoded SHA-256 digest from the header value,
+/// which may list digests computed with several algorithms
+pub fn from_header(value: &str) -> Result<String, InvalidDigest> {
+    digest::from_header(value)
+        .map(hex::encode)
+        .ok_or(InvalidDigest)
+}
+
+/// Hex encoded SHA-256 digest from the `Digest` header if it's given
+pub fn from_headers(headers: &HeaderMap) -> Result<Option<String>, InvalidDigest> {
+    headers
+        .get(DIGEST_HEADER)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| InvalidDigest)
+                .and_then(from_header)
+        })
+        .transpose()
+}
+
+#[derive(Debug, Error)]
+#[error("Digest must be given as SHA-256=<base64>")]
+pub struct InvalidDigest;
@@ -1,103 +1,63 @@
+use crate::blob_store::{BlobError, BlobStore};
+use crate::compression;
+use crate::encryption::{self, BlobKey};
 use actix_web::body::SizedStream;
-use actix_web::error::{BlockingError, Error as ActixError};
-use actix_web::web::{self, Bytes, BytesMut};
-use futures::Stream;
+use actix_web::error::Error as ActixError;
+use actix_web::web::Bytes;
+use futures::{Stream, TryStreamExt};
 use reshare_models::FileInfo;
-use std::future::Future;
-use std::pin::Pin;
-use std::task::{Context, Poll};
 use thiserror::Error;
 
 pub type Result<T, E = DownloadError> = std::result::Result<T, E>;
 
-const MIN_BUF_SIZE_KB: usize = 4;
-const MAX_BUF_SIZE_KB: usize = 8192 * 2;
-
+/// Streams the contents of the file decrypted with the key of its shard, `shard_key`.
+/// Compressed contents are decompressed unless `keep_encoding` is set,
+/// e.g. when the client accepts them as is
 pub async fn download_file_stream(
     file_info: &FileInfo,
+    blob_store: &dyn BlobStore,
+    shard_key: Option<&BlobKey>,
+    keep_encoding: bool,
 ) -> Result<SizedStream<impl Stream<Item = Result<Bytes, ActixError>>>> {
-    let storage_path = file_info.storage_path.clone();
-    let file = web::block(move || std::fs::File::open(storage_path.clone())).await?;
-
-    let stream = DownloadStream::from(file);
-    Ok(SizedStream::new(file_info.size, stream))
-}
-
-struct DownloadStream {
-    state: DownloadState,
-    read_multiplier: usize,
-}
-
-type PendingReadFutOutput = Result<(std::fs::File, BytesMut, usize), BlockingError<std::io::Error>>;
-
-enum DownloadState {
-    NewChunkAvailable(Option<std::fs::File>),
-    PendingRead(Pin<Box<dyn Future<Output = PendingReadFutOutput>>>),
-}
-
-impl From<std::fs::File> for DownloadStream {
-    fn from(file: std::fs::File) -> Self {
-        Self {
-            state: DownloadState::NewChunkAvailable(Some(file)),
-            read_multiplier: MIN_BUF_SIZE_KB,
-        }
+    // Streaming a blob of unexpected size would break the response
+    if blob_store.stat(&file_info.blob_id).await?.size != file_info.stored_size() {
+        log::error!("Stored size of \"{}\" doesn't match", file_info.name);
+        return Err(DownloadError::CorruptedFile);
     }
-}
-
-impl Stream for DownloadStream {
-    type Item = Result<Bytes, ActixError>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        use std::io::prelude::*;
-        const KB: usize = 1024;
-
-        let this = self.get_mut();
 
-        if let DownloadState::NewChunkAvailable(ref mut file) = this.state {
-            let mut file = file.take().unwrap();
-
-            let buf_size = this.read_multiplier * KB;
-            let mut buf = BytesMut::with_capacity(buf_size);
-            unsafe { buf.set_len(buf_size) }
-
-            let fut = web::block(move || {
-                let bytes_read = file.read(&mut buf)?;
-                Ok::<_, std::io::Error>((file, buf, bytes_read))
-            });
-
-            this.state = DownloadState::PendingRead(Box::pin(fut))
+    let (stream, encoded_size) = match &file_info.key_id {
+        Some(key_id) => {
+            let shard_key = shard_key
+                .filter(|shard_key| shard_key.fingerprint() == *key_id)
+                .ok_or(DownloadError::MissingKey)?;
+            let blob_key = encryption::blob_key(file_info, shard_key).map_err(|e| {
+                log::error!("Failed to unseal the key of \"{}\": {}", file_info.name, e);
+                DownloadError::CorruptedFile
+            })?;
+            let stream = blob_store.get(&file_info.blob_id).await?;
+
+            (
+                encryption::decrypt(stream, &blob_key),
+                encryption::plaintext_size(file_info.stored_size()),
+            )
         }
+        None => (
+            blob_store.get(&file_info.blob_id).await?,
+            file_info.stored_size(),
+        ),
+    };
+
+    let (stream, size) = match file_info.encoding {
+        Some(encoding) if !keep_encoding => {
+            (compression::decompress(stream, encoding)?, file_info.size)
+        }
+        _ => (stream, encoded_size),
+    };
 
-        let ret = match this.state {
-            DownloadState::PendingRead(ref mut fut) => match fut.as_mut().poll(cx) {
-                Poll::Ready(Ok((_, _, bytes_read))) if bytes_read == 0 => Poll::Ready(None),
-                Poll::Ready(Ok((file, mut buf, bytes_read))) => {
-                    this.state = DownloadState::NewChunkAvailable(Some(file));
-
-                    if buf.len() == bytes_read {
-                        if this.read_multiplier < MAX_BUF_SIZE_KB {
-                            this.read_multiplier *= 2;
-                        }
-                    } else {
-                        if this.read_multiplier > MIN_BUF_SIZE_KB {
-                            this.read_multiplier /= 2;
-                        }
-                        buf.truncate(bytes_read);
-                    }
-
-                    Poll::Ready(Some(Ok(buf.freeze())))
-                }
-                Poll::Ready(Err(e)) => {
-                    log::error!("Read error {}", e);
-                    Poll::Ready(Some(Err(e.into())))
-                }
-                Poll::Pending => Poll::Pending,
-            },
-            _ => unreachable!(),
-        };
-
-        ret
-    }
+    Ok(SizedStream::new(
+        size,
+        stream.map_err(|e| DownloadError::from(e).into()),
+    ))
 }
 
 #[derive(Debug, Error)]
@@ -105,8 +65,14 @@ pub enum DownloadError {
     #[error("Error reading file")]
     FileReadError {
         #[from]
-        source: BlockingError<std::io::Error>,
+        source: BlobError,
     },
+
+    #[error("File is corrupted")]
+    CorruptedFile,
+
+    #[error("Key the file is encrypted with isn't available")]
+    MissingKey,
 }
 
 impl actix_web::error::ResponseError for DownloadError {
@@ -117,4 +83,16 @@ impl actix_web::error::ResponseError for DownloadError {
             error_msg: self.to_string(),
         })
     }
+
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            Self::FileReadError {
+                source: BlobError::NotFound,
+            } => StatusCode::NOT_FOUND,
+            // The server isn't set up with the key, e.g. the master key was changed
+            Self::MissingKey => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
@@ -0,0 +1,602 @@
+//! Encryption of the stored contents with XChaCha20-Poly1305.
+//! The contents are split into chunks sealed separately, so they can
+//! be streamed in both directions. Nonce of every chunk is made of the
+//! random prefix stored in front of the blob, the number of the chunk
+//! and the flag of the last one, so chunks can't be reordered or dropped.
+//! Data stored by parts is sealed in separate records instead.
+//!
+//! Every blob is encrypted with a random key, which is sealed with the key
+//! derived from the contents, in turn sealed with the key of the shard.
+//! Uploads of the same contents to other shards derive the same content key,
+//! so they share the blob without sharing the keys of their shards. The price
+//! is that anyone holding the contents can tell whether a blob stores them
+//!
+
+use crate::blob_store::{BlobError, ByteStream};
+use actix_web::web::{Bytes, BytesMut};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use futures::{Stream, StreamExt};
+use rand::RngCore;
+use reshare_models::FileInfo;
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub const KEY_LEN: usize = 32;
+
+/// Number of plaintext bytes sealed together
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+const NONCE_PREFIX_LEN: usize = 19;
+const NONCE_LEN: usize = 24;
+// Authenticated along with sealed keys, so that they can't be taken for records
+const SEALED_KEY_AAD: &[u8] = b"reshare-sealed-key";
+
+/// Number of plaintext bytes sealed in a record
+pub const RECORD_DATA_SIZE: usize = CHUNK_SIZE;
+/// Bytes a record takes in addition to its data
+pub const RECORD_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+/// Size of a full record
+pub const RECORD_SIZE: usize = RECORD_DATA_SIZE + RECORD_OVERHEAD;
+
+/// Key of a shard, of some contents or of the blob holding them
+#[derive(Clone)]
+pub struct BlobKey([u8; KEY_LEN]);
+
+impl BlobKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    /// Random key, e.g. one kept in memory until the data is encrypted with a stored one
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Option<Self> {
+        let mut key = [0; KEY_LEN];
+        hex::decode_to_slice(hex_str.trim(), &mut key).ok()?;
+        Some(Self(key))
+    }
+
+    /// Identifies the key without revealing it,
+    /// so the index can tell which key decrypts a blob
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"reshare-blob-key");
+        hasher.update(self.0);
+        hex::encode(&hasher.finalize()[..8])
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+/// Derives the key of the contents as they're read
+pub struct ContentKeyHasher(Sha256);
+
+impl Default for ContentKeyHasher {
+    fn default() -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"reshare-content-key");
+        Self(hasher)
+    }
+}
+
+impl ContentKeyHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> BlobKey {
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&self.0.finalize());
+        BlobKey(key)
+    }
+}
+
+/// Seals `key` with `with`, hex encoded to be kept in the index
+pub fn seal_key(with: &BlobKey, key: &BlobKey) -> std::io::Result<String> {
+    seal(with, SEALED_KEY_AAD, &key.0).map(hex::encode)
+}
+
+pub fn open_key(with: &BlobKey, sealed: &str) -> std::io::Result<BlobKey> {
+    let sealed = hex::decode(sealed).map_err(|_| invalid_data_error("Sealed key is corrupted"))?;
+    let opened = open(with, SEALED_KEY_AAD, &sealed)?;
+
+    let mut key = [0; KEY_LEN];
+    if opened.len() != key.len() {
+        return Err(invalid_data_error("Sealed key is corrupted"));
+    }
+
+    key.copy_from_slice(&opened);
+    Ok(BlobKey(key))
+}
+
+/// Key of the contents of the file, unsealed with the key of its shard
+pub fn content_key(file_info: &FileInfo, shard_key: &BlobKey) -> std::io::Result<BlobKey> {
+    let sealed = file_info
+        .sealed_content_key
+        .as_deref()
+        .ok_or_else(|| invalid_data_error("Key of the contents is missing"))?;
+    open_key(shard_key, sealed)
+}
+
+/// Key the blob holding the contents of the file is encrypted with
+pub fn blob_key(file_info: &FileInfo, shard_key: &BlobKey) -> std::io::Result<BlobKey> {
+    let sealed = file_info
+        .sealed_blob_key
+        .as_deref()
+        .ok_or_else(|| invalid_data_error("Key of the blob is missing"))?;
+    open_key(&content_key(file_info, shard_key)?, sealed)
+}
+
+impl std::fmt::Debug for BlobKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BlobKey({})", self.fingerprint())
+    }
+}
+
+/// Size of the contents stored as `stored_size` bytes once encrypted
+pub fn plaintext_size(stored_size: u64) -> u64 {
+    let sealed_size = stored_size.saturating_sub(NONCE_PREFIX_LEN as u64);
+    // Even empty contents take a chunk
+    let chunks = sealed_size.div_ceil(SEALED_CHUNK_SIZE as u64).max(1);
+    sealed_size.saturating_sub(chunks * TAG_LEN as u64)
+}
+
+/// Encrypts the data as it's streamed
+pub fn encrypt<'a>(data: ByteStream<'a>, key: &BlobKey) -> ByteStream<'a> {
+    let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce_prefix);
+
+    Box::pin(Seal {
+        data,
+        chunks: Chunks {
+            cipher: key.cipher(),
+            nonce_prefix,
+            counter: 0,
+        },
+        buf: BytesMut::new(),
+        header_sent: false,
+        done: false,
+    })
+}
+
+/// Decrypts the data as it's streamed, failing if it's been tampered with
+pub fn decrypt<'a>(data: ByteStream<'a>, key: &BlobKey) -> ByteStream<'a> {
+    Box::pin(Open {
+        data,
+        cipher: key.cipher(),
+        chunks: None,
+        buf: BytesMut::new(),
+        done: false,
+    })
+}
+
+struct Chunks {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+}
+
+impl Chunks {
+    fn next_nonce(&mut self, is_last: bool) -> Result<XNonce, BlobError> {
+        let mut nonce = XNonce::default();
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN + 4].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[NONCE_PREFIX_LEN + 4] = is_last as u8;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("Too many chunks to encrypt"))?;
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, chunk: &[u8], is_last: bool) -> Result<Bytes, BlobError> {
+        let nonce = self.next_nonce(is_last)?;
+        self.cipher
+            .encrypt(&nonce, chunk)
+            .map(Bytes::from)
+            .map_err(|_| invalid_data("Failed to encrypt the data"))
+    }
+
+    fn open(&mut self, chunk: &[u8], is_last: bool) -> Result<Bytes, BlobError> {
+        let nonce = self.next_nonce(is_last)?;
+        self.cipher
+            .decrypt(&nonce, chunk)
+            .map(Bytes::from)
+            .map_err(|_| invalid_data("Encrypted data is corrupted or the key is wrong"))
+    }
+}
+
+/// Seals a record of data stored by parts, e.g. appended to over several requests.
+/// Every record but the last one must hold `RECORD_DATA_SIZE` bytes. The number
+/// of the record is authenticated along with it, so records can't be reordered
+pub fn seal_record(key: &BlobKey, number: u64, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    seal(key, &number.to_be_bytes(), data)
+}
+
+pub fn open_record(key: &BlobKey, number: u64, record: &[u8]) -> std::io::Result<Vec<u8>> {
+    open(key, &number.to_be_bytes(), record)
+}
+
+/// Seals the data with a random nonce stored in front of it
+fn seal(key: &BlobKey, aad: &[u8], data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut nonce = XNonce::default();
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let sealed = key
+        .cipher()
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| invalid_data_error("Failed to encrypt the data"))?;
+
+    let mut record = nonce.to_vec();
+    record.extend_from_slice(&sealed);
+    Ok(record)
+}
+
+fn open(key: &BlobKey, aad: &[u8], record: &[u8]) -> std::io::Result<Vec<u8>> {
+    if record.len() < RECORD_OVERHEAD {
+        return Err(invalid_data_error("Encrypted record is truncated"));
+    }
+
+    let (nonce, sealed) = record.split_at(NONCE_LEN);
+    key.cipher()
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .map_err(|_| invalid_data_error("Encrypted data is corrupted or the key is wrong"))
+}
+
+/// Decrypts the data sealed in records as it's streamed
+pub fn open_records<'a>(data: ByteStream<'a>, key: &BlobKey) -> ByteStream<'a> {
+    let key = key.clone();
+
+    // Fused, as the data is polled again after its end when the last record is short
+    Box::pin(futures::stream::try_unfold(
+        (data.fuse(), BytesMut::new(), 0),
+        move |(mut data, mut buf, number)| {
+            let key = key.clone();
+
+            async move {
+                loop {
+                    if buf.len() >= RECORD_SIZE {
+                        let record = buf.split_to(RECORD_SIZE);
+                        let opened = open_record(&key, number, &record)?;
+                        return Ok(Some((Bytes::from(opened), (data, buf, number + 1))));
+                    }
+
+                    match data.next().await {
+                        Some(chunk) => buf.extend_from_slice(&chunk?),
+                        None if buf.is_empty() => return Ok(None),
+                        None => {
+                            let record = buf.split();
+                            let opened = open_record(&key, number, &record)?;
+                            return Ok(Some((Bytes::from(opened), (data, buf, number + 1))));
+                        }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+fn invalid_data(msg: &str) -> BlobError {
+    invalid_data_error(msg).into()
+}
+
+fn invalid_data_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+struct Seal<'a> {
+    data: ByteStream<'a>,
+    chunks: Chunks,
+    buf: BytesMut,
+    header_sent: bool,
+    done: bool,
+}
+
+impl Stream for Seal<'_> {
+    type Item = Result<Bytes, BlobError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if !self.header_sent {
+            self.header_sent = true;
+            let header = Bytes::copy_from_slice(&self.chunks.nonce_prefix);
+            return Poll::Ready(Some(Ok(header)));
+        }
+
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            // A full chunk is only known not to be the last one once more data comes
+            if self.buf.len() > CHUNK_SIZE {
+                let chunk = self.buf.split_to(CHUNK_SIZE);
+                return Poll::Ready(Some(self.chunks.seal(&chunk, false)));
+            }
+
+            match self.data.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(data))) => self.buf.extend_from_slice(&data),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    self.done = true;
+                    let chunk = self.buf.split();
+                    return Poll::Ready(Some(self.chunks.seal(&chunk, true)));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+struct Open<'a> {
+    data: ByteStream<'a>,
+    cipher: XChaCha20Poly1305,
+    /// Set up once the nonce prefix is read
+    chunks: Option<Chunks>,
+    buf: BytesMut,
+    done: bool,
+}
+
+impl Stream for Open<'_> {
+    type Item = Result<Bytes, BlobError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            if self.chunks.is_none() && self.buf.len() >= NONCE_PREFIX_LEN {
+                let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+                nonce_prefix.copy_from_slice(&self.buf.split_to(NONCE_PREFIX_LEN));
+
+                self.chunks = Some(Chunks {
+                    cipher: self.cipher.clone(),
+                    nonce_prefix,
+                    counter: 0,
+                });
+            }
+
+            if self.chunks.is_some() && self.buf.len() > SEALED_CHUNK_SIZE {
+                let chunk = self.buf.split_to(SEALED_CHUNK_SIZE);
+                let chunks = self.chunks.as_mut().unwrap();
+                return Poll::Ready(Some(chunks.open(&chunk, false)));
+            }
+
+            match self.data.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(data))) => self.buf.extend_from_slice(&data),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    self.done = true;
+                    let chunk = self.buf.split();
+
+                    let opened = match self.chunks.as_mut() {
+                        Some(chunks) => chunks.open(&chunk, true),
+                        None => Err(invalid_data("Encrypted data is truncated")),
+                    };
+
+                    // Empty contents are only known to be intact at this point
+                    return match opened {
+                        Ok(chunk) if chunk.is_empty() => Poll::Ready(None),
+                        opened => Poll::Ready(Some(opened)),
+                    };
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> BlobKey {
+        BlobKey::new([byte; KEY_LEN])
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Streams the data in chunks of the given sizes, repeated until it ends
+    fn split(data: &[u8], sizes: &[usize]) -> ByteStream<'static> {
+        let mut chunks = Vec::new();
+        let mut rest = data;
+
+        for &size in sizes.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+
+            let (chunk, tail) = rest.split_at(size.min(rest.len()));
+            chunks.push(Ok(Bytes::copy_from_slice(chunk)));
+            rest = tail;
+        }
+
+        Box::pin(futures::stream::iter(chunks))
+    }
+
+    async fn collect(mut data: ByteStream<'_>) -> Result<Vec<u8>, BlobError> {
+        let mut collected = Vec::new();
+
+        while let Some(chunk) = data.next().await {
+            collected.extend_from_slice(&chunk?);
+        }
+
+        Ok(collected)
+    }
+
+    async fn seal(data: &[u8], key: &BlobKey) -> Vec<u8> {
+        collect(encrypt(split(data, &[CHUNK_SIZE]), key))
+            .await
+            .unwrap()
+    }
+
+    async fn open(sealed: &[u8], key: &BlobKey) -> Result<Vec<u8>, BlobError> {
+        collect(decrypt(split(sealed, &[CHUNK_SIZE]), key)).await
+    }
+
+    #[actix_rt::test]
+    async fn round_trips() {
+        let lens = [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ];
+        let splits: [&[usize]; 3] = [&[1, 4096], &[7, CHUNK_SIZE + 3, 100], &[1 << 20]];
+
+        for &len in &lens {
+            let data = contents(len);
+
+            for &sizes in &splits {
+                let sealed = collect(encrypt(split(&data, sizes), &key(1)))
+                    .await
+                    .unwrap();
+                assert_eq!(plaintext_size(sealed.len() as u64), len as u64);
+
+                let opened = collect(decrypt(split(&sealed, sizes), &key(1)))
+                    .await
+                    .unwrap();
+                assert_eq!(opened, data, "len {}, chunks {:?}", len, sizes);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rejects_truncated_data() {
+        let sealed = seal(&contents(CHUNK_SIZE + 5), &key(1)).await;
+
+        assert!(open(&sealed[..sealed.len() - 1], &key(1)).await.is_err());
+        assert!(open(&sealed[..NONCE_PREFIX_LEN - 1], &key(1))
+            .await
+            .is_err());
+        assert!(open(&[], &key(1)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_reordered_chunks() {
+        let mut sealed = seal(&contents(3 * CHUNK_SIZE), &key(1)).await;
+
+        let first = NONCE_PREFIX_LEN..NONCE_PREFIX_LEN + SEALED_CHUNK_SIZE;
+        let second = first.end..first.end + SEALED_CHUNK_SIZE;
+        let first_chunk = sealed[first.clone()].to_vec();
+        sealed.copy_within(second.clone(), first.start);
+        sealed[second].copy_from_slice(&first_chunk);
+
+        assert!(open(&sealed, &key(1)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_dropped_last_chunk() {
+        let sealed = seal(&contents(2 * CHUNK_SIZE + 5), &key(1)).await;
+        let without_last = &sealed[..NONCE_PREFIX_LEN + 2 * SEALED_CHUNK_SIZE];
+
+        assert!(open(without_last, &key(1)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_wrong_key() {
+        let sealed = seal(&contents(10), &key(1)).await;
+
+        assert!(open(&sealed, &key(2)).await.is_err());
+        assert_ne!(key(1).fingerprint(), key(2).fingerprint());
+    }
+
+    #[actix_rt::test]
+    async fn opens_records() {
+        let data = contents(2 * RECORD_DATA_SIZE + 5);
+        let mut sealed = Vec::new();
+
+        for (number, record) in data.chunks(RECORD_DATA_SIZE).enumerate() {
+            sealed.extend(seal_record(&key(1), number as u64, record).unwrap());
+        }
+
+        let opened = collect(open_records(split(&sealed, &[1000]), &key(1)))
+            .await
+            .unwrap();
+        assert_eq!(opened, data);
+
+        let last = seal_record(&key(1), 2, b"data").unwrap();
+        assert!(open_record(&key(1), 1, &last).is_err());
+        assert!(open_record(&key(2), 2, &last).is_err());
+        assert_eq!(open_record(&key(1), 2, &last).unwrap(), b"data");
+    }
+
+    fn content_key_of(data: &[u8]) -> BlobKey {
+        let mut hasher = ContentKeyHasher::default();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Sealed keys of the file stored in the shard with the given key
+    fn file_sealing(blob_key: &BlobKey, data: &[u8], shard_key: &BlobKey) -> FileInfo {
+        let content_key = content_key_of(data);
+
+        FileInfo {
+            key_id: Some(shard_key.fingerprint()),
+            sealed_content_key: Some(seal_key(shard_key, &content_key).unwrap()),
+            sealed_blob_key: Some(seal_key(&content_key, blob_key).unwrap()),
+            ..FileInfo::dummy()
+        }
+    }
+
+    #[test]
+    fn shards_share_keys_of_same_contents() {
+        let data = contents(100);
+        let stored_key = BlobKey::generate();
+
+        let first = file_sealing(&stored_key, &data, &key(1));
+        let second = FileInfo {
+            // The blob stored by the first shard is reused by the second one
+            sealed_blob_key: first.sealed_blob_key.clone(),
+            ..file_sealing(&BlobKey::generate(), &data, &key(2))
+        };
+
+        for (file_info, shard_key) in [(&first, key(1)), (&second, key(2))] {
+            let unsealed = blob_key(file_info, &shard_key).unwrap();
+            assert_eq!(unsealed.fingerprint(), stored_key.fingerprint());
+        }
+
+        assert!(blob_key(&second, &key(1)).is_err());
+        assert!(blob_key(&first, &key(2)).is_err());
+
+        // Other contents can't unseal the blob key
+        let other = FileInfo {
+            sealed_blob_key: first.sealed_blob_key.clone(),
+            ..file_sealing(&stored_key, &contents(101), &key(2))
+        };
+        assert!(blob_key(&other, &key(2)).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupted_sealed_keys() {
+        let sealed = seal_key(&key(1), &key(2)).unwrap();
+        assert_eq!(
+            open_key(&key(1), &sealed).unwrap().fingerprint(),
+            key(2).fingerprint()
+        );
+
+        let mut tampered = hex::decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_key(&key(1), &hex::encode(tampered)).is_err());
+        assert!(open_key(&key(1), "not hex").is_err());
+
+        // Sealed keys can't be taken for records and the other way round
+        let record = seal_record(&key(1), 0, &[2; KEY_LEN]).unwrap();
+        assert!(open_key(&key(1), &hex::encode(record)).is_err());
+        assert!(open_record(&key(1), 0, &hex::decode(&sealed).unwrap()).is_err());
+    }
+}
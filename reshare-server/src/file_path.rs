@@ -0,0 +1,52 @@
+//! Relative paths of files inside shards
+//!
+
+pub use reshare_models::file_path::{normalize, normalize_dir, SEPARATOR};
+
+/// Name of the file without its directory
+pub fn file_name(path: &str) -> &str {
+    path.rsplit(SEPARATOR).next().unwrap_or(path)
+}
+
+/// Names the requested file may be stored under, the normalized one first.
+/// Files stored before the names were normalized keep their original names
+pub fn lookup_names(path: &str) -> impl Iterator<Item = String> + '_ {
+    let normalized = normalize(path);
+    let original = Some(path.to_owned()).filter(|path| normalized.as_ref() != Some(path));
+
+    normalized.into_iter().chain(original)
+}
+
+/// Adds the number to the file name before its extension, e.g. `report(1).pdf`.
+/// Zero leaves the path intact
+pub fn numbered(path: &str, num: usize) -> String {
+    if num == 0 {
+        return path.to_owned();
+    }
+
+    let name_start = path.len() - file_name(path).len();
+
+    // Leading dot of hidden files doesn't start an extension
+    match path[name_start..].rfind('.').filter(|&pos| pos > 0) {
+        Some(pos) => {
+            let (stem, extension) = path.split_at(name_start + pos);
+            format!("{}({}){}", stem, num, extension)
+        }
+        None => format!("{}({})", path, num),
+    }
+}
+
+/// Splits a path inside `dir` into the name of the direct child
+/// and whether there is anything nested below it
+pub fn child_of<'a>(dir: &str, path: &'a str) -> Option<(&'a str, bool)> {
+    let rest = if dir.is_empty() {
+        path
+    } else {
+        path.strip_prefix(dir)?.strip_prefix(SEPARATOR)?
+    };
+
+    match rest.find(SEPARATOR) {
+        Some(pos) => Some((&rest[..pos], true)),
+        None => Some((rest, false)),
+    }
+}
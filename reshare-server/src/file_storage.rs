@@ -1,116 +1,907 @@
-use reshare_models::FileInfo;
-use std::collections::{hash_set::Iter, HashMap, HashSet};
+use crate::file_path;
+use crate::shard_key::{KeyHasher, ShardKey};
+use chrono::Local;
+use reshare_models::file_info::{Encoding, FIRST_VERSION};
+use reshare_models::{DirListing, FileInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use thiserror::Error;
 
 pub type Result<T, E = StorageError> = std::result::Result<T, E>;
 
-#[derive(Debug, Clone)]
+const PUBLIC_TREE_NAME: &str = "public";
+const SHARD_TREE_PREFIX: &str = "shard/";
+// Trees keeping the last version of every file name added to the shard
+const VERSIONS_TREE_PREFIX: &str = "versions/";
+// Shards recorded before keyphrases were hashed
+const LEGACY_PRIVATE_TREE_PREFIX: &str = "private/";
+const SALT_KEY: &str = "shard_salt";
+// Files which reached their download limit are reported as gone for this long
+const TOMBSTONE_TTL_DAYS: i64 = 7;
+
+/// Metadata of the stored files. Every shard is guarded by its own lock,
+/// so that readers of a shard don't wait for writers to other ones
+#[derive(Debug)]
 pub struct FileStorage {
-    public: PublicStorage,
+    public: RwLock<Shard>,
     private: PrivateStorage,
+    blobs: Mutex<BlobRefs>,
+    index: MetadataIndex,
+    key_hasher: KeyHasher,
+    additions: Mutex<()>,
+}
+
+/// Metadata of a removed file along with the blob
+/// that is no longer referenced by any other file
+#[derive(Debug)]
+pub struct RemovedFile {
+    pub file_info: FileInfo,
+    pub unused_blob: Option<String>,
 }
 
 impl FileStorage {
-    pub fn new() -> Self {
-        Self {
-            public: PublicStorage::new(),
-            private: PrivateStorage::new(),
+    /// Opens metadata index located at `index_path` and restores
+    /// all the shards recorded there
+    pub fn open(index_path: &Path) -> Result<Self> {
+        let index = MetadataIndex::open(index_path)?;
+        let key_hasher = index.key_hasher()?;
+        index.migrate_legacy_shards(&key_hasher)?;
+
+        let mut public = Shard::default();
+        let mut private = HashMap::<_, Shard>::new();
+        let mut blobs = BlobRefs::default();
+
+        for (shard_key, mut file_info) in index.load()? {
+            // Contents of the files which reached their download limit are already deleted
+            if !file_info.is_exhausted() {
+                // Loaded files hold stored contents, so this can't fail
+                let _ = blobs.acquire(&mut file_info, true);
+            }
+
+            match shard_key {
+                Some(key) => private.entry(key).or_default().insert(file_info),
+                None => public.insert(file_info),
+            }
+        }
+
+        let private = private
+            .into_iter()
+            .map(|(key, shard)| (key, Arc::new(RwLock::new(shard))))
+            .collect();
+
+        Ok(Self {
+            public: RwLock::new(public),
+            private: PrivateStorage(RwLock::new(private)),
+            blobs: Mutex::new(blobs),
+            index,
+            key_hasher,
+            additions: Mutex::new(()),
+        })
+    }
+
+    /// Hasher deriving shard keys for this storage
+    pub fn key_hasher(&self) -> KeyHasher {
+        self.key_hasher.clone()
+    }
+
+    /// Serializes additions of files, so that the checks made before
+    /// adding a file (free names, quotas) still hold when it's added
+    pub fn lock_additions(&self) -> MutexGuard<'_, ()> {
+        lock(&self.additions)
+    }
+
+    pub fn is_file_exists(&self, file_name: &str, shard_key: &Option<ShardKey>) -> bool {
+        // Names of exhausted files can be reused
+        self.get_file(file_name, None, shard_key)
+            .map(|file_info| !file_info.is_exhausted())
+            .unwrap_or(false)
+    }
+
+    /// Returns the given version of the file, the latest one by default.
+    /// Files which reached their download limit are returned without contents
+    pub fn get_file(
+        &self,
+        file_name: &str,
+        version: Option<u32>,
+        shard_key: &Option<ShardKey>,
+    ) -> Option<FileInfo> {
+        self.with_shard(shard_key, |shard| match version {
+            Some(version) => shard.get(file_name, version).cloned(),
+            None => shard.latest(file_name).cloned(),
+        })
+        .flatten()
+    }
+
+    /// Lists available versions of the file from the oldest to the latest
+    pub fn versions(&self, file_name: &str, shard_key: &Option<ShardKey>) -> Vec<FileInfo> {
+        self.with_shard(shard_key, |shard| {
+            shard
+                .versions(file_name)
+                .filter(|file_info| !file_info.is_expired() && !file_info.is_exhausted())
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Adds the file as the next version of the one with the same name.
+    /// If the same contents are already stored, the file is pointed to the
+    /// existing blob. Id of the redundant blob of the file is returned along
+    /// with the stored file if `blob_stored` is set, otherwise the file is
+    /// only added if its contents are already stored
+    pub fn add_file(
+        &self,
+        mut file_info: FileInfo,
+        shard_key: Option<ShardKey>,
+        blob_stored: bool,
+    ) -> Result<(FileInfo, Option<String>)> {
+        let redundant_blob = self.with_shard_mut(&shard_key, true, |shard| {
+            let mut blobs = lock(&self.blobs);
+            let redundant_blob = blobs.acquire(&mut file_info, blob_stored)?;
+
+            // Numbers of removed versions aren't reused, as clients may still refer to them
+            let last_version = self.index.last_version(&file_info.name, &shard_key);
+            file_info.version = match last_version {
+                Ok(last_version) => last_version
+                    .map(|version| version + 1)
+                    .unwrap_or(FIRST_VERSION)
+                    .max(shard.next_version(&file_info.name)),
+                Err(e) => {
+                    blobs.release(&file_info);
+                    return Err(e);
+                }
+            };
+
+            let indexed = self
+                .index
+                .set_last_version(&file_info, &shard_key)
+                .and_then(|_| self.index.insert(&file_info, &shard_key));
+
+            if let Err(e) = indexed {
+                blobs.release(&file_info);
+                return Err(e);
+            }
+
+            shard.insert(file_info.clone());
+            Ok(redundant_blob)
+        })?;
+
+        self.index.flush()?;
+        Ok((file_info, redundant_blob.flatten()))
+    }
+
+    /// Removes the given version of the file, all the versions by default
+    pub fn remove_file(
+        &self,
+        file_name: &str,
+        version: Option<u32>,
+        shard_key: &Option<ShardKey>,
+    ) -> Result<Vec<RemovedFile>> {
+        let removed = self
+            .with_shard_mut(shard_key, false, |shard| {
+                let removed = shard.remove(file_name, version);
+
+                for file_info in &removed {
+                    self.index.remove(file_info, shard_key)?;
+                }
+
+                Ok(removed)
+            })?
+            .unwrap_or_default();
+
+        if removed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.drop_empty_shards();
+        self.index.flush()?;
+
+        Ok(removed
+            .into_iter()
+            .map(|file_info| self.release(file_info))
+            .collect())
+    }
+
+    /// Decrements the number of downloads left for the file. Once the limit
+    /// is reached, the file is kept as a tombstone until it expires, so that
+    /// it's reported as gone. Returns the updated file along with the blob
+    /// that is no longer used by it
+    pub fn count_download(
+        &self,
+        file_info: &FileInfo,
+        shard_key: &Option<ShardKey>,
+    ) -> Result<(FileInfo, Option<String>)> {
+        let counted = self.with_shard_mut(shard_key, false, |shard| {
+            // Concurrent downloads may have changed the file since it was looked up
+            let stored = shard
+                .get(&file_info.name, file_info.version)
+                .ok_or(StorageError::FileNotFound)?;
+
+            let downloads_left = match stored.downloads_left {
+                Some(0) => return Err(StorageError::Exhausted),
+                Some(downloads_left) => downloads_left - 1,
+                None => return Ok((stored.clone(), None)),
+            };
+
+            let mut file_info = FileInfo {
+                downloads_left: Some(downloads_left),
+                ..stored.clone()
+            };
+
+            if file_info.is_exhausted() {
+                let tombstone_expiry = Local::now() + chrono::Duration::days(TOMBSTONE_TTL_DAYS);
+                file_info.expires_at = Some(match file_info.expires_at {
+                    Some(expires_at) => expires_at.min(tombstone_expiry),
+                    None => tombstone_expiry,
+                });
+            }
+
+            self.index.insert(&file_info, shard_key)?;
+
+            let unused_blob = if file_info.is_exhausted() {
+                lock(&self.blobs).release(&file_info)
+            } else {
+                None
+            };
+
+            shard.insert(file_info.clone());
+            Ok((file_info, unused_blob))
+        })?;
+
+        let counted = counted.ok_or(StorageError::FileNotFound)?;
+        self.index.flush()?;
+        Ok(counted)
+    }
+
+    /// Lists the latest versions of the files
+    pub fn list(&self, shard_key: &Option<ShardKey>) -> Result<Vec<FileInfo>> {
+        self.with_shard(shard_key, |shard| {
+            shard
+                .latest_versions()
+                .filter(|file_info| !file_info.is_exhausted())
+                .cloned()
+                .collect()
+        })
+        .ok_or(StorageError::DoesntExist)
+    }
+
+    /// Lists direct children of the directory, `dir_path` must be normalized
+    pub fn list_dir(&self, dir_path: String, shard_key: &Option<ShardKey>) -> Result<DirListing> {
+        let mut dirs = BTreeSet::new();
+        let mut files = Vec::new();
+
+        for file_info in self.list(shard_key)? {
+            match file_path::child_of(&dir_path, &file_info.name) {
+                Some((dir_name, true)) => {
+                    dirs.insert(dir_name.to_owned());
+                }
+                Some((_, false)) => files.push(file_info),
+                None => continue,
+            }
+        }
+
+        if !dir_path.is_empty() && dirs.is_empty() && files.is_empty() {
+            return Err(StorageError::NoSuchDirectory);
         }
+
+        files.sort_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+
+        Ok(DirListing {
+            path: dir_path,
+            dirs: dirs.into_iter().collect(),
+            files,
+        })
     }
 
-    pub fn is_file_exists(&self, file_info: &FileInfo, keyphrase: &Option<String>) -> bool {
-        match keyphrase {
-            Some(key) => self.private.is_file_exists(key, file_info),
-            None => self.public.is_file_exists(file_info),
+    /// Removes all the files which time-to-live has run out, along with
+    /// the tombstones of exhausted files. Returns removed entries so that their contents could be deleted
+    pub fn remove_expired(&self) -> Result<Vec<RemovedFile>> {
+        let mut expired = Vec::new();
+
+        let mut take_expired = |shard: &mut Shard, shard_key: &Option<ShardKey>| {
+            for file_info in shard.take_expired() {
+                match self.index.remove(&file_info, shard_key) {
+                    Ok(()) => expired.push(file_info),
+                    // Kept until the next run, so that the index doesn't refer to deleted contents
+                    Err(e) => {
+                        log::error!(
+                            "Failed to remove expired \"{}\" from the index: {}",
+                            file_info.name,
+                            e
+                        );
+                        shard.insert(file_info);
+                    }
+                }
+            }
+        };
+
+        take_expired(&mut write(&self.public), &None);
+
+        for (shard_key, shard) in read(&self.private.0).iter() {
+            take_expired(&mut write(shard), &Some(shard_key.clone()));
         }
+
+        if expired.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.drop_empty_shards();
+        self.index.flush()?;
+
+        Ok(expired
+            .into_iter()
+            .map(|file_info| self.release(file_info))
+            .collect())
     }
 
-    pub fn get_file(&self, file_name: String, keyphrase: &Option<String>) -> Option<&FileInfo> {
-        let file_info = FileInfo::from_name(file_name);
+    /// All the versions of the files in every shard which still hold their contents
+    pub fn all_files(&self) -> Vec<FileInfo> {
+        let mut files = Vec::new();
+
+        let mut collect = |shard: &Shard| {
+            files.extend(
+                shard
+                    .all()
+                    .filter(|file_info| !file_info.is_exhausted())
+                    .cloned(),
+            );
+        };
+
+        collect(&read(&self.public));
 
-        match keyphrase {
-            Some(key) => self.private.get_file(key, &file_info),
-            None => self.public.get_file(&file_info),
+        for shard in read(&self.private.0).values() {
+            collect(&read(shard));
         }
+
+        files
     }
 
-    pub fn add_file(&mut self, file_info: FileInfo, keyphrase: Option<String>) {
-        match keyphrase {
-            Some(key) => self.private.add_file(key, file_info),
-            None => self.public.add_file(file_info),
+    /// Number of bytes occupied by the contents of all the stored files
+    pub fn total_size(&self) -> u64 {
+        let mut seen_blobs = HashSet::new();
+        let mut total_size = 0;
+
+        let mut count = |shard: &Shard| {
+            total_size += shard
+                .all()
+                .filter(|file_info| !file_info.is_exhausted())
+                .filter(|file_info| seen_blobs.insert(file_info.blob_id.clone()))
+                .map(FileInfo::stored_size)
+                .sum::<u64>();
+        };
+
+        count(&read(&self.public));
+
+        for shard in read(&self.private.0).values() {
+            count(&read(shard));
         }
+
+        total_size
     }
 
-    pub fn list(&self, keyphrase: &Option<String>) -> Result<impl Iterator<Item = &FileInfo>> {
-        match keyphrase {
-            Some(key) => self.private.list(key).ok_or(StorageError::DoesntExist),
-            None => Ok(self.public.list()),
+    /// Number of bytes occupied by the contents of all the file versions in the shard
+    pub fn shard_size(&self, shard_key: &Option<ShardKey>) -> u64 {
+        self.with_shard(shard_key, |shard| {
+            shard
+                .all()
+                .filter(|file_info| !file_info.is_exhausted())
+                .map(FileInfo::stored_size)
+                .sum()
+        })
+        .unwrap_or(0)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.index.flush()
+    }
+
+    /// Runs `f` holding the read lock of the shard, `None` if the shard doesn't exist
+    fn with_shard<T>(
+        &self,
+        shard_key: &Option<ShardKey>,
+        f: impl FnOnce(&Shard) -> T,
+    ) -> Option<T> {
+        match shard_key {
+            Some(key) => {
+                let private = read(&self.private.0);
+                let shard = private.get(key)?;
+                let result = f(&read(shard));
+                Some(result)
+            }
+            None => Some(f(&read(&self.public))),
+        }
+    }
+
+    /// Runs `f` holding the write lock of the shard. Missing private shards are
+    /// created if `create` is set, otherwise `None` is returned for them
+    fn with_shard_mut<T>(
+        &self,
+        shard_key: &Option<ShardKey>,
+        create: bool,
+        f: impl FnOnce(&mut Shard) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let key = match shard_key {
+            Some(key) => key,
+            None => return f(&mut write(&self.public)).map(Some),
+        };
+
+        // The map stays locked, so that the shard can't be dropped in the meantime
+        {
+            let private = read(&self.private.0);
+
+            if let Some(shard) = private.get(key) {
+                return f(&mut write(shard)).map(Some);
+            }
+        }
+
+        if !create {
+            return Ok(None);
+        }
+
+        let mut private = write(&self.private.0);
+        let shard = private.entry(key.clone()).or_default();
+        let result = f(&mut write(shard)).map(Some);
+        result
+    }
+
+    fn drop_empty_shards(&self) {
+        write(&self.private.0).retain(|_, shard| !read(shard).is_empty());
+    }
+
+    fn release(&self, file_info: FileInfo) -> RemovedFile {
+        // Exhausted files have already released their blobs
+        let unused_blob = if file_info.is_exhausted() {
+            None
+        } else {
+            lock(&self.blobs).release(&file_info)
+        };
+
+        RemovedFile {
+            file_info,
+            unused_blob,
         }
     }
 }
 
-type Storage = HashSet<FileInfo>;
+// A panic while holding a lock leaves the data usable,
+// so poisoning is ignored instead of failing every request
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reference counts of blobs keyed by the digest of their contents,
+/// so that identical files share a single blob across all the shards.
+/// Encrypted blobs are shared by the files of every shard encrypting its
+/// contents, as their keys are sealed with the keys derived from the contents.
+/// Unencrypted ones are only shared by the files stored unencrypted
+#[derive(Debug, Clone, Default)]
+struct BlobRefs(HashMap<(String, bool), BlobRef>);
 
 #[derive(Debug, Clone)]
-struct PublicStorage(Storage);
+struct BlobRef {
+    blob_id: String,
+    /// The same contents may be stored with another encoding
+    encoding: Option<Encoding>,
+    stored_size: u64,
+    /// Key of an encrypted blob sealed with the content key
+    sealed_blob_key: Option<String>,
+    refs: usize,
+}
+
+impl BlobRefs {
+    /// Registers a reference to the contents of the file. If another blob
+    /// holds the same contents, the file is pointed to it and id of its own
+    /// blob is returned if it's `stored`, as it's redundant.
+    /// Fails if the contents aren't stored anywhere
+    fn acquire(&mut self, file_info: &mut FileInfo, stored: bool) -> Result<Option<String>> {
+        let key = match ref_key(file_info) {
+            Some(key) => key,
+            None if stored => return Ok(None),
+            None => return Err(StorageError::BlobNotStored),
+        };
+
+        let blob_ref = match self.0.get_mut(&key) {
+            Some(blob_ref) => blob_ref,
+            None if stored => {
+                self.0.insert(
+                    key,
+                    BlobRef {
+                        blob_id: file_info.blob_id.clone(),
+                        encoding: file_info.encoding,
+                        stored_size: file_info.stored_size(),
+                        sealed_blob_key: file_info.sealed_blob_key.clone(),
+                        refs: 1,
+                    },
+                );
+                return Ok(None);
+            }
+            None => return Err(StorageError::BlobNotStored),
+        };
+        blob_ref.refs += 1;
+
+        if blob_ref.blob_id == file_info.blob_id {
+            return Ok(None);
+        }
+
+        file_info.encoding = blob_ref.encoding;
+        file_info.stored_size = Some(blob_ref.stored_size);
+        file_info.sealed_blob_key = blob_ref.sealed_blob_key.clone();
+        let own_blob = std::mem::replace(&mut file_info.blob_id, blob_ref.blob_id.clone());
 
-impl PublicStorage {
-    fn new() -> Self {
-        Self(Storage::new())
+        Ok(Some(own_blob).filter(|_| stored))
     }
 
-    fn list(&self) -> Iter<'_, FileInfo> {
-        self.0.iter()
+    /// Drops a reference to the contents of the file.
+    /// Returns id of the blob if it's not referenced anymore
+    fn release(&mut self, file_info: &FileInfo) -> Option<String> {
+        let key = match ref_key(file_info) {
+            Some(key) => key,
+            // Files uploaded before deduplication own their blobs
+            None => return Some(file_info.blob_id.clone()),
+        };
+
+        let blob_ref = self.0.get_mut(&key)?;
+        blob_ref.refs -= 1;
+
+        if blob_ref.refs == 0 {
+            self.0.remove(&key).map(|blob_ref| blob_ref.blob_id)
+        } else {
+            None
+        }
     }
+}
+
+fn ref_key(file_info: &FileInfo) -> Option<(String, bool)> {
+    Some((file_info.digest.clone()?, file_info.key_id.is_some()))
+}
 
-    fn is_file_exists(&self, file_info: &FileInfo) -> bool {
-        self.0.contains(file_info)
+/// Files of a shard, every name holds its versions in ascending order
+#[derive(Debug, Clone, Default)]
+struct Shard(HashMap<String, Vec<FileInfo>>);
+
+impl Shard {
+    fn all(&self) -> impl Iterator<Item = &FileInfo> {
+        self.0.values().flatten()
+    }
+
+    fn versions(&self, file_name: &str) -> impl DoubleEndedIterator<Item = &FileInfo> {
+        self.0.get(file_name).into_iter().flatten()
+    }
+
+    /// The latest versions which time-to-live hasn't run out
+    fn latest_versions(&self) -> impl Iterator<Item = &FileInfo> {
+        self.0
+            .keys()
+            .filter_map(move |file_name| self.latest(file_name))
     }
 
-    fn get_file(&self, file_info: &FileInfo) -> Option<&FileInfo> {
-        self.0.get(file_info)
+    /// The latest version which time-to-live hasn't run out
+    fn latest(&self, file_name: &str) -> Option<&FileInfo> {
+        self.versions(file_name)
+            .rev()
+            .find(|file_info| !file_info.is_expired())
     }
 
-    fn add_file(&mut self, file_info: FileInfo) {
-        self.0.insert(file_info);
+    fn get(&self, file_name: &str, version: u32) -> Option<&FileInfo> {
+        self.versions(file_name)
+            .find(|file_info| file_info.version == version)
+            .filter(|file_info| !file_info.is_expired())
+    }
+
+    fn next_version(&self, file_name: &str) -> u32 {
+        self.versions(file_name)
+            .last()
+            .map(|file_info| file_info.version + 1)
+            .unwrap_or(FIRST_VERSION)
+    }
+
+    /// Adds the version of the file or replaces the stored one
+    fn insert(&mut self, file_info: FileInfo) {
+        let versions = self.0.entry(file_info.name.clone()).or_default();
+
+        match versions.binary_search_by_key(&file_info.version, |stored| stored.version) {
+            Ok(pos) => versions[pos] = file_info,
+            Err(pos) => versions.insert(pos, file_info),
+        }
+    }
+
+    fn remove(&mut self, file_name: &str, version: Option<u32>) -> Vec<FileInfo> {
+        let version = match version {
+            Some(version) => version,
+            None => return self.0.remove(file_name).unwrap_or_default(),
+        };
+
+        let versions = match self.0.get_mut(file_name) {
+            Some(versions) => versions,
+            None => return Vec::new(),
+        };
+
+        let removed = versions
+            .iter()
+            .position(|file_info| file_info.version == version)
+            .map(|pos| versions.remove(pos))
+            .into_iter()
+            .collect();
+
+        if versions.is_empty() {
+            self.0.remove(file_name);
+        }
+
+        removed
+    }
+
+    fn take_expired(&mut self) -> Vec<FileInfo> {
+        let mut expired = Vec::new();
+
+        for versions in self.0.values_mut() {
+            let (removed, kept) = std::mem::take(versions)
+                .into_iter()
+                .partition(FileInfo::is_expired);
+
+            *versions = kept;
+            expired.extend::<Vec<_>>(removed);
+        }
+
+        self.0.retain(|_, versions| !versions.is_empty());
+        expired
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
+type SharedShard = Arc<RwLock<Shard>>;
+
+#[derive(Debug)]
+struct PrivateStorage(RwLock<HashMap<ShardKey, SharedShard>>);
+
+/// Durable copy of the shards metadata.
+/// Every shard is kept in a separate tree keyed by file name and version
 #[derive(Debug, Clone)]
-struct PrivateStorage(HashMap<String, Storage>);
+struct MetadataIndex {
+    db: sled::Db,
+}
 
-impl PrivateStorage {
-    fn new() -> Self {
-        Self(HashMap::new())
+/// `FileInfo` doesn't serialize the blob id as it must not be
+/// exposed to clients, so the index stores it alongside
+#[derive(Debug, Serialize, Deserialize)]
+struct FileRecord {
+    #[serde(flatten)]
+    file_info: FileInfo,
+    // Records written before blob stores were introduced hold
+    // an absolute path of the file in the work dir
+    #[serde(alias = "storage_path")]
+    blob_id: String,
+    #[serde(default)]
+    key_id: Option<String>,
+    #[serde(default)]
+    sealed_content_key: Option<String>,
+    #[serde(default)]
+    sealed_blob_key: Option<String>,
+}
+
+impl MetadataIndex {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
     }
 
-    fn list(&self, shard_name: &str) -> Option<Iter<'_, FileInfo>> {
-        self.0.get(shard_name).map(|storage| storage.iter())
+    /// Restores the hasher from the salt kept in the index,
+    /// generating a new salt on the first run
+    fn key_hasher(&self) -> Result<KeyHasher> {
+        let salt = match self.db.get(SALT_KEY)? {
+            Some(salt) => salt,
+            None => {
+                let salt = KeyHasher::generate_salt();
+                self.db.insert(SALT_KEY, &salt)?;
+                self.flush()?;
+                salt.as_ref().into()
+            }
+        };
+
+        KeyHasher::new(&salt).ok_or(StorageError::CorruptedSalt)
     }
 
-    fn get_file(&self, shard_name: &str, file_info: &FileInfo) -> Option<&FileInfo> {
-        self.0
-            .get(shard_name)
-            .and_then(|storage| storage.get(file_info))
+    /// Moves shards named by plaintext keyphrases to the trees named by their keys
+    fn migrate_legacy_shards(&self, key_hasher: &KeyHasher) -> Result<()> {
+        for tree_name in self.db.tree_names() {
+            let keyphrase = match std::str::from_utf8(&tree_name) {
+                Ok(name) if name.starts_with(LEGACY_PRIVATE_TREE_PREFIX) => {
+                    &name[LEGACY_PRIVATE_TREE_PREFIX.len()..]
+                }
+                _ => continue,
+            };
+
+            let shard_key = key_hasher
+                .hash(keyphrase)
+                .map_err(|_| StorageError::CorruptedSalt)?;
+            let legacy_tree = self.db.open_tree(&tree_name)?;
+            let tree = self.tree(&Some(shard_key))?;
+
+            for entry in legacy_tree.iter() {
+                let (key, value) = entry?;
+                tree.insert(key, value)?;
+            }
+
+            self.db.drop_tree(&tree_name)?;
+        }
+
+        self.flush()
     }
 
-    fn is_file_exists(&self, shard_name: &str, file_info: &FileInfo) -> bool {
-        self.0
-            .get(shard_name)
-            .map(|storage| storage.contains(file_info))
-            .unwrap_or(false)
+    fn load(&self) -> Result<Vec<(Option<ShardKey>, FileInfo)>> {
+        let mut files = Vec::new();
+
+        for tree_name in self.db.tree_names() {
+            let shard_key = match std::str::from_utf8(&tree_name) {
+                Ok(PUBLIC_TREE_NAME) => None,
+                Ok(name) if name.starts_with(SHARD_TREE_PREFIX) => {
+                    match ShardKey::from_hex(&name[SHARD_TREE_PREFIX.len()..]) {
+                        Some(shard_key) => Some(shard_key),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            let tree = self.db.open_tree(&tree_name)?;
+
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                let record: FileRecord = serde_json::from_slice(&value)?;
+
+                // Records written before versioning are keyed by name only
+                let record_key = record_key(&record.file_info);
+                if key != record_key {
+                    tree.remove(&key)?;
+                    tree.insert(record_key, value)?;
+                }
+
+                let blob_id = Path::new(&record.blob_id)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or(record.blob_id);
+
+                let file_info = FileInfo {
+                    blob_id,
+                    key_id: record.key_id,
+                    sealed_content_key: record.sealed_content_key,
+                    sealed_blob_key: record.sealed_blob_key,
+                    ..record.file_info
+                };
+
+                files.push((shard_key.clone(), file_info));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn insert(&self, file_info: &FileInfo, shard_key: &Option<ShardKey>) -> Result<()> {
+        let record = FileRecord {
+            file_info: file_info.clone(),
+            blob_id: file_info.blob_id.clone(),
+            key_id: file_info.key_id.clone(),
+            sealed_content_key: file_info.sealed_content_key.clone(),
+            sealed_blob_key: file_info.sealed_blob_key.clone(),
+        };
+
+        self.tree(shard_key)?
+            .insert(record_key(file_info), serde_json::to_vec(&record)?)?;
+        Ok(())
+    }
+
+    fn remove(&self, file_info: &FileInfo, shard_key: &Option<ShardKey>) -> Result<()> {
+        let tree = self.tree(shard_key)?;
+        tree.remove(record_key(file_info))?;
+
+        if shard_key.is_some() && tree.is_empty() {
+            self.db.drop_tree(tree.name())?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Version of the last file with the name added to the shard, even if it's removed
+    fn last_version(&self, file_name: &str, shard_key: &Option<ShardKey>) -> Result<Option<u32>> {
+        let version = match self.versions_tree(shard_key)?.get(file_name)? {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+
+        let mut bytes = [0; 4];
+        if version.len() != bytes.len() {
+            return Err(StorageError::CorruptedVersion);
+        }
+
+        bytes.copy_from_slice(&version);
+        Ok(Some(u32::from_be_bytes(bytes)))
+    }
+
+    fn set_last_version(&self, file_info: &FileInfo, shard_key: &Option<ShardKey>) -> Result<()> {
+        self.versions_tree(shard_key)?
+            .insert(&file_info.name, &file_info.version.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn tree(&self, shard_key: &Option<ShardKey>) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(tree_name(shard_key))?)
+    }
+
+    fn versions_tree(&self, shard_key: &Option<ShardKey>) -> Result<sled::Tree> {
+        Ok(self
+            .db
+            .open_tree(format!("{}{}", VERSIONS_TREE_PREFIX, tree_name(shard_key)))?)
     }
+}
 
-    fn add_file(&mut self, shard_name: String, file_info: FileInfo) {
-        let storage = self.0.entry(shard_name).or_insert(Storage::new());
-        storage.insert(file_info);
+fn tree_name(shard_key: &Option<ShardKey>) -> String {
+    match shard_key {
+        Some(key) => format!("{}{}", SHARD_TREE_PREFIX, key.to_hex()),
+        None => PUBLIC_TREE_NAME.to_owned(),
     }
 }
 
+/// Name followed by the version, so that versions of a file are stored together
+fn record_key(file_info: &FileInfo) -> Vec<u8> {
+    let mut key = file_info.name.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(&file_info.version.to_be_bytes());
+    key
+}
+
 #[derive(Debug, Error)]
 pub enum StorageError {
     #[error("Requested storage doesn't exist")]
     DoesntExist,
+
+    #[error("Requested directory doesn't exist")]
+    NoSuchDirectory,
+
+    #[error("Requested file doesn't exist")]
+    FileNotFound,
+
+    #[error("File has reached its download limit")]
+    Exhausted,
+
+    #[error("Contents of the file aren't stored")]
+    BlobNotStored,
+
+    #[error("Metadata index failure")]
+    Index {
+        #[from]
+        source: sled::Error,
+    },
+
+    #[error("Corrupted metadata record")]
+    CorruptedRecord {
+        #[from]
+        source: serde_json::Error,
+    },
+
+    #[error("Corrupted shard key salt")]
+    CorruptedSalt,
+
+    #[error("Corrupted file version counter")]
+    CorruptedVersion,
 }
 
 impl actix_web::error::ResponseError for StorageError {
@@ -121,6 +912,15 @@ impl actix_web::error::ResponseError for StorageError {
     }
 
     fn status_code(&self) -> actix_web::http::StatusCode {
-        actix_web::http::StatusCode::NOT_FOUND
+        use actix_web::http::StatusCode;
+        match self {
+            Self::DoesntExist | Self::NoSuchDirectory | Self::FileNotFound => StatusCode::NOT_FOUND,
+            Self::Exhausted => StatusCode::GONE,
+            Self::BlobNotStored
+            | Self::Index { .. }
+            | Self::CorruptedRecord { .. }
+            | Self::CorruptedSalt
+            | Self::CorruptedVersion => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
@@ -0,0 +1,112 @@
+//! Consistency check between the metadata index and the stored blobs
+//!
+
+use crate::blob_store::{BlobError, BlobStore};
+use crate::file_storage::FileStorage;
+use crate::uploader;
+use reshare_models::FileInfo;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Blobs no file refers to, e.g. left by interrupted uploads
+    pub orphaned_blobs: usize,
+    pub deleted_blobs: usize,
+    /// Files which contents are gone
+    pub missing_blobs: usize,
+    /// Files which contents differ in size from the recorded one
+    pub size_mismatches: usize,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_blobs == 0 && self.missing_blobs == 0 && self.size_mismatches == 0
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} orphaned blobs ({} deleted), {} missing blobs, {} size mismatches",
+            self.orphaned_blobs, self.deleted_blobs, self.missing_blobs, self.size_mismatches
+        )
+    }
+}
+
+/// Reports files with broken contents and blobs not referred to by any file.
+/// Orphaned blobs are deleted unless `dry_run` is set. Must not run
+/// alongside uploads, as their blobs aren't referred to until they finish
+pub async fn check(
+    storage: &FileStorage,
+    blob_store: &dyn BlobStore,
+    dry_run: bool,
+) -> Result<Report, BlobError> {
+    let mut report = Report::default();
+    let files = storage.all_files();
+
+    // Files with the same contents share a blob
+    let mut blobs = HashMap::<_, Vec<&FileInfo>>::new();
+    for file_info in &files {
+        blobs
+            .entry(file_info.blob_id.as_str())
+            .or_default()
+            .push(file_info);
+    }
+
+    for (blob_id, files) in &blobs {
+        let stored_size = match blob_store.stat(blob_id).await {
+            Ok(stat) => Some(stat.size),
+            Err(BlobError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        for file_info in files {
+            match stored_size {
+                None => {
+                    log::warn!(
+                        "Contents of \"{}\", version {} are missing",
+                        file_info.name,
+                        file_info.version
+                    );
+                    report.missing_blobs += 1;
+                }
+                Some(size) if size != file_info.stored_size() => {
+                    log::warn!(
+                        "Contents of \"{}\", version {} take {} bytes instead of {}",
+                        file_info.name,
+                        file_info.version,
+                        size,
+                        file_info.stored_size()
+                    );
+                    report.size_mismatches += 1;
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    for blob_id in blob_store.list().await? {
+        // Anything else in the storage doesn't belong to the server
+        if blobs.contains_key(blob_id.as_str()) || !uploader::is_blob_id(&blob_id) {
+            continue;
+        }
+
+        report.orphaned_blobs += 1;
+
+        if dry_run {
+            log::warn!("Orphaned blob: {}", blob_id);
+            continue;
+        }
+
+        match blob_store.delete(&blob_id).await {
+            Ok(()) => {
+                log::info!("Deleted orphaned blob: {}", blob_id);
+                report.deleted_blobs += 1;
+            }
+            Err(e) => log::error!("Failed to delete orphaned blob {}: {}", blob_id, e),
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,85 @@
+//! Keyphrases of private shards passed along with requests
+//!
+
+use actix_web::{dev::Payload, http::header, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use reshare_models::KEYPHRASE_HEADER;
+use thiserror::Error;
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+const LEGACY_PRIVATE_PREFIX: &str = "/api/private/";
+const REDACTED: &str = "<redacted>";
+
+/// Keyphrase taken from `X-Reshare-Key` or `Authorization: Bearer` header,
+/// `None` when the request targets the public shard
+#[derive(Debug, Clone, Default)]
+pub struct Keyphrase(pub Option<String>);
+
+impl Keyphrase {
+    fn from_request(req: &HttpRequest) -> Result<Self, KeyphraseError> {
+        let headers = req.headers();
+
+        let encoded = match headers.get(KEYPHRASE_HEADER) {
+            Some(value) => value.to_str().map_err(|_| KeyphraseError::Malformed)?,
+            None => match headers.get(header::AUTHORIZATION) {
+                Some(value) => {
+                    let value = value.to_str().map_err(|_| KeyphraseError::Malformed)?;
+                    value
+                        .strip_prefix(BEARER_PREFIX)
+                        .ok_or(KeyphraseError::Malformed)?
+                }
+                None => return Ok(Self(None)),
+            },
+        };
+
+        let keyphrase =
+            urlencoding::decode(encoded.trim()).map_err(|_| KeyphraseError::Malformed)?;
+        Ok(Self(Some(keyphrase).filter(|s| !s.is_empty())))
+    }
+}
+
+impl FromRequest for Keyphrase {
+    type Error = KeyphraseError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Keyphrase::from_request(req))
+    }
+}
+
+/// Body of the form the web client downloads private files with
+#[derive(Debug, serde::Deserialize)]
+pub struct KeyphraseForm {
+    pub keyphrase: String,
+}
+
+/// Hides keyphrases in paths of the legacy private routes
+pub fn redact_path(path: &str) -> String {
+    match path.strip_prefix(LEGACY_PRIVATE_PREFIX) {
+        Some(rest) => match rest.find('/') {
+            Some(pos) => format!("{}{}{}", LEGACY_PRIVATE_PREFIX, REDACTED, &rest[pos..]),
+            None => format!("{}{}", LEGACY_PRIVATE_PREFIX, REDACTED),
+        },
+        None => path.to_owned(),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum KeyphraseError {
+    #[error("Malformed keyphrase header")]
+    Malformed,
+}
+
+impl actix_web::error::ResponseError for KeyphraseError {
+    fn error_response(&self) -> actix_web::HttpResponse {
+        actix_web::dev::HttpResponseBuilder::new(self.status_code()).json(reshare_models::Error {
+            error_msg: self.to_string(),
+        })
+    }
+
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
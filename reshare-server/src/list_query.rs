@@ -0,0 +1,338 @@
+//! Ordering, filtering and pagination of listed files
+//!
+
+use chrono::{DateTime, Local};
+use reshare_models::{FileInfo, SortKey, SortOrder};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use thiserror::Error;
+
+pub type Result<T, E = ListQueryError> = std::result::Result<T, E>;
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    /// Directory to list, all the files are listed if it's not given
+    pub path: Option<String>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Name prefix, or a glob if it contains `*` or `?`
+    pub filter: Option<String>,
+    /// Position right after the last file of the previous page
+    pub cursor: Option<String>,
+    /// Max number of files on a page, all the files by default
+    pub limit: Option<usize>,
+}
+
+/// Files of a single page along with the cursor of the next one
+#[derive(Debug)]
+pub struct Page {
+    pub files: Vec<FileInfo>,
+    pub next_cursor: Option<String>,
+}
+
+impl ListQuery {
+    /// Picks the requested page of the files located in `dir_path`.
+    /// Names are matched against the filter relative to that directory
+    pub fn apply(&self, mut files: Vec<FileInfo>, dir_path: &str) -> Result<Page> {
+        let limit = match self.limit {
+            Some(0) => return Err(ListQueryError::InvalidLimit),
+            limit => limit,
+        };
+
+        let cursor = self.cursor.as_deref().map(Cursor::decode).transpose()?;
+
+        if let Some(filter) = self.filter.as_deref().filter(|s| !s.is_empty()) {
+            files.retain(|file_info| matches(filter, relative_name(&file_info.name, dir_path)));
+        }
+
+        if let Some(cursor) = cursor {
+            files.retain(|file_info| self.compare(file_info.into(), cursor.as_ref()).is_gt());
+        }
+
+        files.sort_by(|lhs, rhs| self.compare(lhs.into(), rhs.into()));
+
+        let next_cursor = match limit {
+            Some(limit) if files.len() > limit => {
+                files.truncate(limit);
+                files
+                    .last()
+                    .map(|file_info| Cursor::from(file_info).encode())
+            }
+            _ => None,
+        };
+
+        Ok(Page { files, next_cursor })
+    }
+
+    fn compare(&self, lhs: SortFields, rhs: SortFields) -> Ordering {
+        let ordering = match self.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => lhs.size.cmp(&rhs.size),
+            SortKey::UploadDate => lhs.upload_date.cmp(&rhs.upload_date),
+        }
+        // Names are unique within a listing, which makes the order total
+        .then_with(|| lhs.name.cmp(rhs.name));
+
+        match self.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+fn relative_name<'a>(name: &'a str, dir_path: &str) -> &'a str {
+    if dir_path.is_empty() {
+        return name;
+    }
+
+    name.strip_prefix(dir_path)
+        .and_then(|name| name.strip_prefix('/'))
+        .unwrap_or(name)
+}
+
+fn matches(filter: &str, name: &str) -> bool {
+    if filter.contains(['*', '?']) {
+        let pattern: Vec<_> = filter.chars().collect();
+        let name: Vec<_> = name.chars().collect();
+        glob_matches(&pattern, &name)
+    } else {
+        name.starts_with(filter)
+    }
+}
+
+/// `*` matches any sequence of characters and `?` matches a single one
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Positions to retry from once the last `*` swallows one more character
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Fields the files are ordered by
+#[derive(Debug, Clone, Copy)]
+struct SortFields<'a> {
+    name: &'a str,
+    size: u64,
+    upload_date: DateTime<Local>,
+}
+
+impl<'a> From<&'a FileInfo> for SortFields<'a> {
+    fn from(file_info: &'a FileInfo) -> Self {
+        Self {
+            name: &file_info.name,
+            size: file_info.size,
+            upload_date: file_info.upload_date,
+        }
+    }
+}
+
+/// Sort fields of the last file of a page. Keeping the fields instead
+/// of the position lets pages stay consistent while files come and go
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    name: String,
+    size: u64,
+    upload_date: DateTime<Local>,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("Cursor is always serializable"))
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        hex::decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(ListQueryError::InvalidCursor)
+    }
+
+    fn as_ref(&self) -> SortFields<'_> {
+        SortFields {
+            name: &self.name,
+            size: self.size,
+            upload_date: self.upload_date,
+        }
+    }
+}
+
+impl From<&FileInfo> for Cursor {
+    fn from(file_info: &FileInfo) -> Self {
+        Self {
+            name: file_info.name.clone(),
+            size: file_info.size,
+            upload_date: file_info.upload_date,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ListQueryError {
+    #[error("Invalid page cursor")]
+    InvalidCursor,
+
+    #[error("Page limit must be a positive number")]
+    InvalidLimit,
+}
+
+impl actix_web::error::ResponseError for ListQueryError {
+    fn error_response(&self) -> actix_web::HttpResponse {
+        actix_web::dev::HttpResponseBuilder::new(self.status_code()).json(reshare_models::Error {
+            error_msg: self.to_string(),
+        })
+    }
+
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+
+    fn sorted_by(sort: SortKey, order: SortOrder) -> ListQuery {
+        ListQuery {
+            path: None,
+            sort,
+            order,
+            filter: None,
+            cursor: None,
+            limit: None,
+        }
+    }
+
+    fn file(name: &str, size: u64) -> FileInfo {
+        FileInfo {
+            size,
+            ..FileInfo::from_name(name.to_owned())
+        }
+    }
+
+    fn names(files: &[FileInfo]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|file_info| file_info.name.as_str())
+            .collect()
+    }
+
+    fn glob(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<_> = pattern.chars().collect();
+        let name: Vec<_> = name.chars().collect();
+        glob_matches(&pattern, &name)
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "name"));
+        assert!(glob("*.txt", "notes.txt"));
+        assert!(glob("?otes.*", "notes.txt"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(glob("a*b?c", "abXbYc"));
+        assert!(glob("**a", "bba"));
+
+        assert!(!glob("", "name"));
+        assert!(!glob("*.txt", "notes.txt.bak"));
+        assert!(!glob("?", ""));
+        assert!(!glob("??", "a"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+        assert!(!glob("a*b*c", "XbYbZc"));
+    }
+
+    #[test]
+    fn filters_relative_names() {
+        let files = vec![
+            file("dir/notes.txt", 1),
+            file("dir/photo.jpg", 1),
+            file("notes.md", 1),
+        ];
+
+        let mut query = sorted_by(SortKey::Name, SortOrder::Asc);
+        query.filter = Some("no".to_owned());
+        let page = query.apply(files.clone(), "dir").unwrap();
+        assert_eq!(names(&page.files), ["dir/notes.txt", "notes.md"]);
+
+        query.filter = Some("*.txt".to_owned());
+        let page = query.apply(files, "dir").unwrap();
+        assert_eq!(names(&page.files), ["dir/notes.txt"]);
+    }
+
+    #[test]
+    fn pages_through_equal_sizes() {
+        let files: Vec<_> = (0..7)
+            .map(|i| file(&format!("file-{}", i), if i == 3 { 2 } else { 1 }))
+            .collect();
+
+        for &order in &[SortOrder::Asc, SortOrder::Desc] {
+            let mut query = sorted_by(SortKey::Size, order);
+            let expected = names(&query.apply(files.clone(), "").unwrap().files)
+                .into_iter()
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            assert_eq!(expected.len(), files.len());
+
+            query.limit = Some(2);
+            let mut listed = Vec::new();
+            loop {
+                let page = query.apply(files.clone(), "").unwrap();
+                assert!(page.files.len() <= 2);
+                listed.extend(names(&page.files).into_iter().map(str::to_owned));
+
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => break,
+                }
+            }
+
+            assert_eq!(listed, expected);
+            let largest_first = order == SortOrder::Desc;
+            assert_eq!(listed[0] == "file-3", largest_first);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_pages() {
+        let files = vec![file("name", 1)];
+
+        let mut query = sorted_by(SortKey::Name, SortOrder::Asc);
+        query.limit = Some(0);
+        let error = query.apply(files.clone(), "").unwrap_err();
+        assert!(matches!(error, ListQueryError::InvalidLimit));
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+
+        for cursor in &["", "not hex", "7b7d"] {
+            let mut query = sorted_by(SortKey::Name, SortOrder::Asc);
+            query.cursor = Some(cursor.to_string());
+            let error = query.apply(files.clone(), "").unwrap_err();
+            assert!(matches!(error, ListQueryError::InvalidCursor));
+            assert_eq!(error.error_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+}
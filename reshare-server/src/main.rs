@@ -1,139 +1,905 @@
+mod blob_store;
+mod compression;
+mod config;
+mod content_type;
+mod digest;
 mod downloader;
+mod encryption;
+mod file_path;
 mod file_storage;
+mod fsck;
+mod keyphrase;
+mod list_query;
 mod multipart;
+mod quota;
+mod reaper;
+mod shard_key;
+mod tus;
 mod uploader;
 
 use actix_files::Files;
 use actix_multipart::Multipart;
 use actix_web::{
-    body::Body, dev::HttpResponseBuilder, error::ResponseError, get, http::header,
-    middleware::Logger, post, web, App, Error, HttpResponse, HttpServer,
+    body::Body, delete, dev::HttpResponseBuilder, error::ResponseError, get, head, http::header,
+    middleware::Logger, options, patch, post, put, web, App, Error, HttpRequest, HttpResponse,
+    HttpServer,
 };
-use file_storage::FileStorage;
-use reshare_models::{FileInfo, FileUploadStatus};
-use std::sync::Mutex;
-use uploader::UploadForm;
+use blob_store::{BlobStore, StagedBlob};
+use compression::Compression;
+use config::{Command, Retention, Settings};
+use encryption::BlobKey;
+use file_storage::{FileStorage, StorageError};
+use keyphrase::{Keyphrase, KeyphraseForm};
+use list_query::ListQuery;
+use multipart::{MultipartForm, MultipartPart};
+use quota::Quotas;
+use reshare_models::tus::{
+    FILETYPE_KEY, TUS_EXTENSIONS, TUS_EXTENSION_HEADER, TUS_MAX_SIZE_HEADER, TUS_RESUMABLE_HEADER,
+    TUS_VERSION, TUS_VERSION_HEADER, UPLOAD_LENGTH_HEADER, UPLOAD_OFFSET_HEADER,
+};
+use reshare_models::upload::KEYPHRASE_FIELD;
+use reshare_models::{DirListing, FileInfo, FileUploadStatus, DIGEST_HEADER, NEXT_CURSOR_HEADER};
+use shard_key::{KeyHasher, ShardKey};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tus::{PartialUploads, TusError, UploadInfo};
+use uploader::{CommittedBlob, ConflictPolicy, SavedFile, SizeLimits, UploadLimits, UploadOptions};
 
-type Storage = Mutex<FileStorage>;
+const METADATA_INDEX_NAME: &str = "index";
+const PARTIAL_UPLOADS_DIR_NAME: &str = "uploads";
+const REAPER_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[get("/list")]
-async fn list(storage: web::Data<Storage>) -> Result<HttpResponse, Error> {
-    list_impl(storage, None).await
+async fn list(
+    web::Query(query): web::Query<ListQuery>,
+    storage: web::Data<FileStorage>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    list_impl(query, storage, key_hasher, keyphrase).await
 }
 
 #[get("/private/{keyphrase}")]
 async fn list_private(
-    storage: web::Data<Storage>,
+    web::Query(query): web::Query<ListQuery>,
+    storage: web::Data<FileStorage>,
+    key_hasher: web::Data<KeyHasher>,
     web::Path(keyphrase): web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    list_impl(storage, Some(keyphrase)).await
+    list_impl(query, storage, key_hasher, Some(keyphrase)).await
 }
 
 async fn list_impl(
-    storage: web::Data<Storage>,
+    query: ListQuery,
+    storage: web::Data<FileStorage>,
+    key_hasher: web::Data<KeyHasher>,
     keyphrase: Option<String>,
 ) -> Result<HttpResponse, Error> {
-    let guard = storage.lock().unwrap();
+    let shard_key = key_hasher.shard_key(keyphrase).await?;
+    let dir_path = match &query.path {
+        Some(path) => Some(
+            file_path::normalize_dir(path).ok_or_else(|| HttpResponse::BadRequest().finish())?,
+        ),
+        None => None,
+    };
+
+    let mut response = HttpResponse::Ok();
+
+    match dir_path {
+        Some(dir_path) => {
+            let listing = storage.list_dir(dir_path, &shard_key)?;
+            let page = query.apply(listing.files, &listing.path)?;
+            set_next_cursor(&mut response, page.next_cursor);
+
+            Ok(response.json(DirListing {
+                files: page.files,
+                ..listing
+            }))
+        }
+        None => {
+            let page = query.apply(storage.list(&shard_key)?, "")?;
+            set_next_cursor(&mut response, page.next_cursor);
+
+            Ok(response.json(page.files))
+        }
+    }
+}
 
-    let files: Vec<_> = guard.list(&keyphrase)?.collect();
-    Ok(HttpResponse::Ok().json(files))
+fn set_next_cursor(response: &mut HttpResponseBuilder, next_cursor: Option<String>) {
+    if let Some(cursor) = next_cursor {
+        response.header(NEXT_CURSOR_HEADER, cursor);
+    }
 }
 
 #[post("/upload")]
-async fn upload(form_data: Multipart, storage: web::Data<Storage>) -> Result<HttpResponse, Error> {
-    let mut upload_form = UploadForm::try_from_multipart(form_data).await?;
-    let mut statuses = Vec::new();
+#[allow(clippy::too_many_arguments)]
+async fn upload(
+    req: HttpRequest,
+    form_data: Multipart,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    quotas: web::Data<Quotas>,
+    upload_limits: web::Data<UploadLimits>,
+    retention: web::Data<Retention>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    upload_limits.check_content_length(req.headers())?;
 
-    let keyphrase = upload_form.keyphrase;
+    let compression = compression(&req);
+    let mut form = MultipartForm::from(form_data);
+    let mut fields = HashMap::new();
+    // Contents of the files are discarded unless they're stored
+    let mut saved: Vec<SavedFile> = Vec::new();
+    let mut request_received = 0;
 
-    while let Some(file) = upload_form.files.next_file().await? {
-        let upload_status = uploader::save_file(file.filename, file.file_stream).await;
-        let mut storage = storage.lock().unwrap();
+    // The keyphrase may also come as a form field, until then the shard is unknown
+    let mut keys = match keyphrase {
+        Some(keyphrase) => Some(key_hasher.keys(Some(keyphrase)).await?),
+        None => None,
+    };
+    // Keys of the files received before that are sealed with the key of the public
+    // shard, or one only kept in memory, and sealed again once the shard is known
+    let pending_key = match &keys {
+        Some(_) => None,
+        None => Some(
+            key_hasher
+                .keys(None)
+                .await?
+                .blob_key
+                .unwrap_or_else(BlobKey::generate),
+        ),
+    };
 
-        statuses.push(upload_status);
+    loop {
+        let saved_file = match form.next_part().await {
+            Ok(None) => break,
+            Ok(Some(MultipartPart::Text { name, value })) => {
+                if name == KEYPHRASE_FIELD && keys.is_none() && !value.is_empty() {
+                    keys = Some(key_hasher.keys(Some(value)).await?);
+                } else {
+                    fields.insert(name, value);
+                }
+                continue;
+            }
+            Ok(Some(MultipartPart::File(mut file))) => {
+                let size_limits = SizeLimits {
+                    // Checked again once the file is stored. The whole server is
+                    // limited until the shard is known
+                    quota: match &keys {
+                        Some(keys) => quotas.remaining(&storage, &keys.shard_key),
+                        None => quotas.remaining(&storage, &None),
+                    },
+                    upload: **upload_limits,
+                    request_received,
+                };
 
-        match statuses.last_mut().unwrap() {
-            Ok(status_file_info) => {
-                // Ensure unique name
-                let file_info = std::iter::once(status_file_info.clone())
-                    .chain((1..).map(|num| FileInfo {
-                        name: format!("{}({})", status_file_info.name, num),
-                        ..status_file_info.clone()
-                    }))
-                    .skip_while(|file_info| storage.is_file_exists(file_info, &keyphrase))
-                    .next()
-                    .unwrap();
-
-                log::info!(
-                    "Uploaded file: \"{}\", upload size: {}",
-                    file_info.name,
-                    file_info.size
-                );
-
-                storage.add_file(file_info.clone(), keyphrase.clone());
-                *status_file_info = file_info;
+                let compression_level =
+                    compression.level_for(&file.filename, file.content_type.as_deref());
+
+                uploader::save_file(
+                    file.filename,
+                    file.file_stream.as_mut(),
+                    blob_store.as_ref(),
+                    size_limits,
+                    file.digest.as_deref(),
+                    compression_level,
+                    match &keys {
+                        Some(keys) => keys.blob_key.as_ref(),
+                        None => pending_key.as_ref(),
+                    },
+                )
+                .await
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match saved_file {
+            Ok(saved_file) => {
+                request_received += saved_file.file_info.size;
+                saved.push(saved_file);
             }
-            Err(err) => {
-                return Err(HttpResponseBuilder::new(err.status_code())
+            Err(e) => {
+                let status = e.status_code();
+                let statuses = saved
+                    .iter()
+                    .map(|_| Err(uploader::UploadError::Discarded))
+                    .chain(std::iter::once(Err(e)))
+                    .collect();
+
+                return Err(HttpResponseBuilder::new(status)
                     .json(transform_statuses(statuses))
                     .into());
             }
         }
     }
 
+    let options = UploadOptions::parse_form(&fields)?;
+    let keys = match keys {
+        Some(keys) => keys,
+        None => key_hasher.keys(None).await?,
+    };
+
+    let mut statuses = Vec::new();
+    let mut files = saved.into_iter();
+    let key_id = keys.blob_key.as_ref().map(BlobKey::fingerprint);
+
+    for saved_file in &mut files {
+        // Files received before the keyphrase are encrypted with the pending key
+        let encrypted = match (options.check_digest(&saved_file.file_info), &pending_key) {
+            (Ok(()), Some(pending_key)) if saved_file.file_info.key_id != key_id => {
+                uploader::reencrypt(saved_file, &blob_store, pending_key, keys.blob_key.as_ref())
+                    .await
+            }
+            (Ok(()), _) => Ok(saved_file),
+            (Err(e), _) => Err(e),
+        };
+
+        let upload_status = match encrypted {
+            Ok(saved_file) => {
+                store_file(
+                    options.apply(saved_file.file_info, &retention),
+                    saved_file.blob,
+                    &keys.shard_key,
+                    options.on_conflict,
+                    &storage,
+                    &blob_store,
+                    &quotas,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        let failure_status = upload_status.as_ref().err().map(|e| e.status_code());
+        statuses.push(upload_status);
+
+        if let Some(status) = failure_status {
+            statuses.extend(files.map(|_| Err(uploader::UploadError::Discarded)));
+
+            return Err(HttpResponseBuilder::new(status)
+                .json(transform_statuses(statuses))
+                .into());
+        }
+    }
+
     Ok(HttpResponse::Ok().json(transform_statuses(statuses)))
 }
 
-#[get("/download/{file_name}")]
+/// Stores the request body as the contents of the file
+#[allow(clippy::too_many_arguments)]
+#[put("/upload/{file_name:.+}")]
+async fn upload_raw(
+    req: HttpRequest,
+    web::Path(file_name): web::Path<String>,
+    payload: web::Payload,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    quotas: web::Data<Quotas>,
+    upload_limits: web::Data<UploadLimits>,
+    retention: web::Data<Retention>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    upload_limits.check_content_length(req.headers())?;
+
+    let web::Query(query) = web::Query::<HashMap<String, String>>::from_query(req.query_string())?;
+    let options = UploadOptions::parse(&query)?;
+    let file_name = file_path::normalize(&file_name)
+        .ok_or(uploader::UploadError::InvalidFilePath { path: file_name })?;
+    let expected_digest =
+        digest::from_headers(req.headers()).map_err(uploader::UploadError::from)?;
+
+    let keys = key_hasher.keys(keyphrase).await?;
+    let size_limits = SizeLimits {
+        quota: quotas.remaining(&storage, &keys.shard_key),
+        upload: **upload_limits,
+        request_received: 0,
+    };
+
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok());
+    let compression_level = compression(&req).level_for(&file_name, content_type);
+
+    let saved = uploader::save_file(
+        file_name,
+        payload,
+        blob_store.as_ref(),
+        size_limits,
+        expected_digest.as_deref(),
+        compression_level,
+        keys.blob_key.as_ref(),
+    )
+    .await?;
+
+    let file_info = store_file(
+        options.apply(saved.file_info, &retention),
+        saved.blob,
+        &keys.shard_key,
+        options.on_conflict,
+        &storage,
+        &blob_store,
+        &quotas,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(file_info))
+}
+
+#[options("/tus")]
+async fn tus_options(upload_limits: web::Data<UploadLimits>) -> HttpResponse {
+    let mut response = HttpResponse::NoContent();
+    response
+        .header(TUS_RESUMABLE_HEADER, TUS_VERSION)
+        .header(TUS_VERSION_HEADER, TUS_VERSION)
+        .header(TUS_EXTENSION_HEADER, TUS_EXTENSIONS);
+
+    if let Some(max_size) = upload_limits.max_file_size {
+        response.header(TUS_MAX_SIZE_HEADER, max_size);
+    }
+
+    response.finish()
+}
+
+#[post("/tus")]
+async fn tus_create(
+    req: HttpRequest,
+    uploads: web::Data<PartialUploads>,
+    storage: web::Data<FileStorage>,
+    quotas: web::Data<Quotas>,
+    upload_limits: web::Data<UploadLimits>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    tus::check_version(req.headers())?;
+
+    let keys = key_hasher.keys(keyphrase).await?;
+    let shard_key = &keys.shard_key;
+    let info = UploadInfo::from_headers(req.headers(), &keys)?;
+    upload_limits
+        .check_file_size(info.length)
+        .map_err(TusError::from)?;
+
+    // Rejected before any data is sent, the quota is checked again once it's received.
+    // Compressed files may take less space than their length, so they're only checked then
+    let content_type = info.options.get(FILETYPE_KEY).map(String::as_str);
+    let is_compressed = compression(&req)
+        .level_for(&info.file_name, content_type)
+        .is_some();
+    let size_limit = quotas.remaining(&storage, shard_key);
+    if !is_compressed && size_limit.map(|limit| info.length > limit).unwrap_or(false) {
+        return Err(TusError::from(uploader::UploadError::QuotaExceeded).into());
+    }
+
+    let id = uploads.create(&info).await?;
+    log::info!(
+        "Started upload of \"{}\", size: {}",
+        info.file_name,
+        info.length
+    );
+
+    Ok(HttpResponse::Created()
+        .header(TUS_RESUMABLE_HEADER, TUS_VERSION)
+        .header(header::LOCATION, format!("/api/tus/{}", id))
+        .finish())
+}
+
+#[head("/tus/{id}")]
+async fn tus_offset(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    uploads: web::Data<PartialUploads>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    tus::check_version(req.headers())?;
+
+    let shard_key = key_hasher.shard_key(keyphrase).await?;
+    let (info, offset) = uploads.get(&id).await?;
+    info.ensure_shard(&shard_key)?;
+
+    Ok(HttpResponse::Ok()
+        .header(TUS_RESUMABLE_HEADER, TUS_VERSION)
+        .header(UPLOAD_OFFSET_HEADER, offset)
+        .header(UPLOAD_LENGTH_HEADER, info.length)
+        .header(header::CACHE_CONTROL, "no-store")
+        .finish())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[patch("/tus/{id}")]
+async fn tus_append(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    payload: web::Payload,
+    uploads: web::Data<PartialUploads>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    quotas: web::Data<Quotas>,
+    retention: web::Data<Retention>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    tus::check_version(req.headers())?;
+    let offset = tus::upload_offset(req.headers())?;
+
+    let keys = key_hasher.keys(keyphrase).await?;
+    let shard_key = keys.shard_key;
+    // Checked before locking, so that other clients can't supersede the upload
+    let (info, _) = uploads.get(&id).await?;
+    info.ensure_shard(&shard_key)?;
+    let data_key = info.data_key(keys.blob_key.as_ref())?;
+    let guard = uploads.lock_append(&id)?;
+
+    let received = uploads
+        .append(&guard, info.length, offset, data_key, payload)
+        .await?;
+
+    if received == info.length {
+        guard.finish()?;
+
+        // The size is already limited by the upload length
+        let size_limits = SizeLimits {
+            quota: quotas.remaining(&storage, &shard_key),
+            ..SizeLimits::default()
+        };
+        let content_type = info.options.get(FILETYPE_KEY).map(String::as_str);
+        let compression_level = compression(&req).level_for(&info.file_name, content_type);
+
+        let saved = uploader::save_file(
+            info.file_name.clone(),
+            uploads.data(&id, data_key).await?,
+            blob_store.as_ref(),
+            size_limits,
+            info.digest.as_deref(),
+            compression_level,
+            keys.blob_key.as_ref(),
+        )
+        .await;
+
+        let stored = match saved {
+            Ok(saved) => match info.options() {
+                Ok(options) => {
+                    store_file(
+                        options.apply(saved.file_info, &retention),
+                        saved.blob,
+                        &shard_key,
+                        options.on_conflict,
+                        &storage,
+                        &blob_store,
+                        &quotas,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+
+        // Resending the data wouldn't help, so the upload is over either way
+        if let Err(e) = uploads.remove(&id).await {
+            log::error!("Failed to remove partial upload {}: {}", id, e);
+        }
+
+        stored.map_err(TusError::from)?;
+    }
+
+    Ok(HttpResponse::NoContent()
+        .header(TUS_RESUMABLE_HEADER, TUS_VERSION)
+        .header(UPLOAD_OFFSET_HEADER, received)
+        .finish())
+}
+
+#[delete("/tus/{id}")]
+async fn tus_terminate(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    uploads: web::Data<PartialUploads>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    tus::check_version(req.headers())?;
+
+    let shard_key = key_hasher.shard_key(keyphrase).await?;
+    let _guard = uploads.lock(&id)?;
+    let (info, _) = uploads.get(&id).await?;
+    info.ensure_shard(&shard_key)?;
+
+    uploads.remove(&id).await?;
+    log::info!("Terminated upload of \"{}\"", info.file_name);
+
+    Ok(HttpResponse::NoContent()
+        .header(TUS_RESUMABLE_HEADER, TUS_VERSION)
+        .finish())
+}
+
+/// Compression of the uploaded files. Read from the app data by the
+/// upload handlers, as some of them already take as many extractors as allowed
+fn compression(req: &HttpRequest) -> Compression {
+    req.app_data::<web::Data<Compression>>()
+        .map(|compression| ***compression)
+        .unwrap_or_default()
+}
+
+/// Adds the file with the contents written to `blob` to the shard. The blob
+/// is only committed if the same contents aren't stored yet, it's discarded
+/// if the file can't be added
+async fn store_file(
+    file_info: FileInfo,
+    blob: Box<dyn StagedBlob>,
+    shard_key: &Option<ShardKey>,
+    on_conflict: ConflictPolicy,
+    storage: &FileStorage,
+    blob_store: &Arc<dyn BlobStore>,
+    quotas: &Quotas,
+) -> uploader::Result<FileInfo> {
+    let blob_id = file_info.blob_id.clone();
+
+    let add_file = |file_info: FileInfo, blob_stored: bool| {
+        let _additions = storage.lock_additions();
+
+        // Concurrent uploads may have taken the space in the meantime
+        let size_limit = quotas.remaining(storage, shard_key);
+        if size_limit
+            .map(|limit| file_info.stored_size() > limit)
+            .unwrap_or(false)
+        {
+            Err(uploader::UploadError::QuotaExceeded)
+        } else {
+            let file_name = match on_conflict {
+                ConflictPolicy::NewVersion => file_info.name.clone(),
+                // Ensure unique name
+                ConflictPolicy::KeepBoth => (0..)
+                    .map(|num| file_path::numbered(&file_info.name, num))
+                    .find(|name| !storage.is_file_exists(name, shard_key))
+                    .unwrap(),
+            };
+
+            storage
+                .add_file(
+                    FileInfo {
+                        name: file_name,
+                        ..file_info
+                    },
+                    shard_key.clone(),
+                    blob_stored,
+                )
+                .map_err(uploader::UploadError::from)
+        }
+    };
+
+    // Duplicates are pointed to the stored contents, so their blobs are never committed
+    let stored = match add_file(file_info.clone(), false) {
+        Err(uploader::UploadError::Storage {
+            source: StorageError::BlobNotStored,
+        }) => {
+            let blob = CommittedBlob::commit(blob, &blob_id, blob_store).await?;
+
+            let stored = add_file(file_info, true);
+            match stored {
+                Ok(_) => blob.keep(),
+                Err(_) => blob.delete().await,
+            }
+
+            stored
+        }
+        stored => stored,
+    };
+
+    let (file_info, redundant_blob) = stored?;
+    log::info!(
+        "Uploaded file: \"{}\", version: {}, upload size: {}",
+        file_info.name,
+        file_info.version,
+        file_info.size
+    );
+
+    if file_info.blob_id != blob_id {
+        log::info!("Contents of \"{}\" are already stored", file_info.name);
+    }
+
+    // Another upload of the same contents was stored in the meantime
+    if let Some(blob_id) = redundant_blob {
+        if let Err(e) = blob_store.delete(&blob_id).await {
+            log::error!("Failed to delete blob {}: {}", blob_id, e);
+        }
+    }
+
+    Ok(file_info)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct VersionQuery {
+    /// Version of the file, the latest one is used if it's not given
+    version: Option<u32>,
+}
+
+#[get("/download/{file_name:.+}")]
 async fn download(
+    req: HttpRequest,
+    web::Path(file_name): web::Path<String>,
+    web::Query(query): web::Query<VersionQuery>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    download_impl(
+        req,
+        file_name,
+        query.version,
+        keyphrase,
+        storage,
+        blob_store,
+        key_hasher,
+    )
+    .await
+}
+
+/// Lets browsers download private files without exposing the keyphrase in the url
+#[post("/download/{file_name:.+}")]
+async fn download_form(
+    req: HttpRequest,
     web::Path(file_name): web::Path<String>,
-    storage: web::Data<Storage>,
+    web::Query(query): web::Query<VersionQuery>,
+    web::Form(form): web::Form<KeyphraseForm>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
 ) -> Result<HttpResponse, Error> {
-    download_impl(file_name, None, storage).await
+    let keyphrase = Some(form.keyphrase).filter(|s| !s.is_empty());
+    download_impl(
+        req,
+        file_name,
+        query.version,
+        keyphrase,
+        storage,
+        blob_store,
+        key_hasher,
+    )
+    .await
 }
 
-#[get("/private/{keyphrase}/{file_name}")]
+#[get("/private/{keyphrase}/{file_name:.+}")]
 async fn download_private(
+    req: HttpRequest,
     web::Path((keyphrase, file_name)): web::Path<(String, String)>,
-    storage: web::Data<Storage>,
+    web::Query(query): web::Query<VersionQuery>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
 ) -> Result<HttpResponse, Error> {
-    download_impl(file_name, Some(keyphrase), storage).await
+    download_impl(
+        req,
+        file_name,
+        query.version,
+        Some(keyphrase),
+        storage,
+        blob_store,
+        key_hasher,
+    )
+    .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn download_impl(
+    req: HttpRequest,
     file_name: String,
+    version: Option<u32>,
     keyphrase: Option<String>,
-    storage: web::Data<Storage>,
-) -> Result<HttpResponse, Error> {
-    let file_info = {
-        let guard = storage.lock().unwrap();
-        guard
-            .get_file(file_name, &keyphrase)
-            .cloned()
-            .ok_or_else(|| HttpResponse::NotFound().finish())?
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
+) -> Result<HttpResponse, Error> {
+    let keys = key_hasher.keys(keyphrase).await?;
+    let shard_key = keys.shard_key;
+    let file_info = file_path::lookup_names(&file_name)
+        .find_map(|file_name| storage.get_file(&file_name, version, &shard_key))
+        .ok_or_else(|| HttpResponse::NotFound().finish())?;
+
+    if file_info.is_exhausted() {
+        return Err(StorageError::Exhausted.into());
+    }
+
+    // Compressed contents are sent as is to the clients able to decode them
+    let sent_encoding = file_info
+        .encoding
+        .filter(|&encoding| compression::is_accepted(req.headers(), encoding));
+
+    // Opened before the download is counted, so that it isn't used up by a failure
+    let file_stream = downloader::download_file_stream(
+        &file_info,
+        blob_store.as_ref(),
+        keys.blob_key.as_ref(),
+        sent_encoding.is_some(),
+    )
+    .await?;
+    let response_body: Body = file_stream.into();
+
+    let (file_info, unused_blob) = match file_info.downloads_left {
+        Some(_) => storage.count_download(&file_info, &shard_key)?,
+        None => (file_info, None),
     };
 
     let content_dispostion = header::ContentDisposition {
         disposition: header::DispositionType::Attachment,
-        parameters: vec![header::DispositionParam::Filename(file_info.name.clone())],
+        parameters: vec![header::DispositionParam::Filename(
+            file_path::file_name(&file_info.name).to_owned(),
+        )],
     };
 
-    let file_stream = downloader::download_file_stream(&file_info).await?;
-    let response_body: Body = file_stream.into();
+    if file_info.is_exhausted() {
+        log::info!("Download limit reached: \"{}\"", file_info.name);
+    }
 
-    Ok(HttpResponse::Ok()
+    // The file is already opened, so its contents can be removed
+    if let Some(blob_id) = unused_blob {
+        if let Err(e) = blob_store.delete(&blob_id).await {
+            log::error!("Failed to delete \"{}\": {}", file_info.name, e);
+        }
+    }
+
+    let mut response = HttpResponse::Ok();
+    response
         .header(header::CONTENT_DISPOSITION, content_dispostion)
-        .body(response_body))
+        .content_type(
+            file_info
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream"),
+        )
+        // Browsers must not guess another type than the detected one
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+
+    if file_info.encoding.is_some() {
+        response.header(header::VARY, "Accept-Encoding");
+    }
+
+    if let Some(encoding) = sent_encoding {
+        response.header(header::CONTENT_ENCODING, encoding.as_str());
+    }
+
+    if let Some(digest) = &file_info.digest {
+        // Each encoding of the contents is a separate representation
+        let etag = match sent_encoding {
+            Some(encoding) => format!("\"{}-{}\"", digest, encoding.as_str()),
+            None => format!("\"{}\"", digest),
+        };
+        response.header(header::ETAG, etag);
+
+        // Lets clients verify the received contents. The digest is of the decoded ones,
+        // so it would describe a different representation than the encoded one sent
+        if sent_encoding.is_none() {
+            if let Some(digest) = digest::to_header(digest) {
+                response.header(DIGEST_HEADER, digest);
+            }
+        }
+    }
+
+    Ok(response.body(response_body))
+}
+
+#[delete("/download/{file_name:.+}")]
+async fn remove(
+    web::Path(file_name): web::Path<String>,
+    web::Query(query): web::Query<VersionQuery>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    remove_impl(
+        file_name,
+        query.version,
+        keyphrase,
+        storage,
+        blob_store,
+        key_hasher,
+    )
+    .await
+}
+
+#[delete("/private/{keyphrase}/{file_name:.+}")]
+async fn remove_private(
+    web::Path((keyphrase, file_name)): web::Path<(String, String)>,
+    web::Query(query): web::Query<VersionQuery>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
+) -> Result<HttpResponse, Error> {
+    remove_impl(
+        file_name,
+        query.version,
+        Some(keyphrase),
+        storage,
+        blob_store,
+        key_hasher,
+    )
+    .await
+}
+
+async fn remove_impl(
+    file_name: String,
+    version: Option<u32>,
+    keyphrase: Option<String>,
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    key_hasher: web::Data<KeyHasher>,
+) -> Result<HttpResponse, Error> {
+    let shard_key = key_hasher.shard_key(keyphrase).await?;
+    let mut removed = Vec::new();
+
+    for file_name in file_path::lookup_names(&file_name) {
+        removed = storage.remove_file(&file_name, version, &shard_key)?;
+
+        if !removed.is_empty() {
+            break;
+        }
+    }
+
+    if removed.is_empty() {
+        return Err(HttpResponse::NotFound().finish().into());
+    }
+
+    let mut removed_files = Vec::new();
+
+    for removed in removed {
+        log::info!(
+            "Removed file: \"{}\", version: {}",
+            removed.file_info.name,
+            removed.file_info.version
+        );
+
+        if let Some(blob_id) = removed.unused_blob {
+            if let Err(e) = blob_store.delete(&blob_id).await {
+                log::error!("Failed to delete \"{}\": {}", removed.file_info.name, e);
+            }
+        }
+
+        removed_files.push(removed.file_info);
+    }
+
+    Ok(HttpResponse::Ok().json(removed_files))
+}
+
+#[get("/versions/{file_name:.+}")]
+async fn versions(
+    web::Path(file_name): web::Path<String>,
+    storage: web::Data<FileStorage>,
+    key_hasher: web::Data<KeyHasher>,
+    Keyphrase(keyphrase): Keyphrase,
+) -> Result<HttpResponse, Error> {
+    let shard_key = key_hasher.shard_key(keyphrase).await?;
+    let versions = file_path::lookup_names(&file_name)
+        .map(|file_name| storage.versions(&file_name, &shard_key))
+        .find(|versions| !versions.is_empty())
+        .unwrap_or_default();
+
+    if versions.is_empty() {
+        return Err(HttpResponse::NotFound().finish().into());
+    }
+
+    Ok(HttpResponse::Ok().json(versions))
 }
 
 #[get("/upload")]
-fn dummy_uploader(_storage: web::Data<Storage>) -> HttpResponse {
+fn dummy_uploader(_storage: web::Data<FileStorage>) -> HttpResponse {
     let html = r#"<html>
         <head><title>Upload Test</title></head>
         <body>
             <form target="/" method="post" enctype="multipart/form-data">
                 <input type="text" name="keyphrase"/>
+                <input type="number" name="ttl" min="1" placeholder="TTL, seconds"/>
+                <input type="number" name="max_downloads" min="1" placeholder="Max downloads"/>
+                <select name="on_conflict">
+                    <option value="new_version">New version</option>
+                    <option value="keep_both">Keep both</option>
+                </select>
                 <input type="file" multiple name="file"/>
+                <input type="text" name="description" placeholder="Description"/>
+                <input type="text" name="tags" placeholder="Tags, comma separated"/>
                 <button type="submit">Submit</button>
             </form>
         </body>
@@ -146,34 +912,119 @@ fn dummy_uploader(_storage: web::Data<Storage>) -> HttpResponse {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "reshare_server=debug,actix_web=info");
-    env_logger::init();
-
-    let listen_addr = std::env::vars()
-        .find(|(key, _)| key == "PORT")
-        .map(|(_, val)| format!("0.0.0.0:{}", val))
-        .unwrap_or_else(|| "0.0.0.0:8080".to_owned());
-
-    let file_storage = web::Data::new(Mutex::new(FileStorage::new()));
-
-    let app = move || {
-        App::new()
-            .app_data(file_storage.clone())
-            .wrap(Logger::new("%a '%U' -> %s in %Ts"))
-            .service(
-                web::scope("/api")
-                    .service(list)
-                    .service(list_private)
-                    .service(download)
-                    .service(download_private)
-                    .service(upload)
-                    .service(dummy_uploader),
-            )
-            .service(Files::new("/", "./web_page").index_file("index.html"))
+    // Reported before logging is set up, so the message must be readable on its own
+    let args = config::parse_args();
+    let settings = Settings::load(&args)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    env_logger::Builder::new()
+        .parse_filters(&settings.log_level)
+        .init();
+
+    let work_dir = settings.storage_dir.as_path();
+    std::fs::create_dir_all(work_dir)?;
+
+    let file_storage =
+        FileStorage::open(&work_dir.join(METADATA_INDEX_NAME)).map_err(std::io::Error::other)?;
+    let key_hasher = web::Data::new(
+        file_storage
+            .key_hasher()
+            .with_master_key(settings.master_key.clone()),
+    );
+    let file_storage = web::Data::new(file_storage);
+    let blob_store = web::Data::from(blob_store::open(&settings.blob_store, work_dir)?);
+    let partial_uploads = web::Data::new(PartialUploads::open(
+        work_dir.join(PARTIAL_UPLOADS_DIR_NAME),
+    )?);
+
+    if let Some(Command::Fsck { dry_run }) = args.command {
+        let report = fsck::check(&file_storage, blob_store.as_ref(), dry_run)
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        println!("{}", report);
+
+        if !report.is_clean() {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    // Nothing is being uploaded yet, so unreferenced blobs are safe to delete
+    if settings.startup_check {
+        match fsck::check(&file_storage, blob_store.as_ref(), false).await {
+            Ok(report) if report.is_clean() => {}
+            Ok(report) => log::warn!("Consistency check: {}", report),
+            Err(e) => log::error!("Consistency check failed: {}", e),
+        }
+    }
+
+    let quotas = web::Data::new(settings.quotas);
+    let upload_limits = web::Data::new(settings.upload_limits);
+    let retention = web::Data::new(settings.retention);
+    let compression = web::Data::new(settings.compression);
+    let legacy_routes = settings.legacy_routes;
+    let static_dir = settings.static_dir.clone();
+
+    reaper::spawn(
+        file_storage.clone(),
+        blob_store.clone(),
+        partial_uploads.clone(),
+        REAPER_PERIOD,
+    );
+
+    let app = {
+        let file_storage = file_storage.clone();
+        move || {
+            App::new()
+                .app_data(file_storage.clone())
+                .app_data(blob_store.clone())
+                .app_data(quotas.clone())
+                .app_data(upload_limits.clone())
+                .app_data(retention.clone())
+                .app_data(compression.clone())
+                .app_data(key_hasher.clone())
+                .app_data(partial_uploads.clone())
+                .wrap(
+                    Logger::new("%a '%{path}xi' -> %s in %Ts")
+                        .custom_request_replace("path", |req| keyphrase::redact_path(req.path())),
+                )
+                .service({
+                    let api = web::scope("/api")
+                        .service(list)
+                        .service(download)
+                        .service(download_form)
+                        .service(remove)
+                        .service(versions)
+                        .service(upload)
+                        .service(upload_raw)
+                        .service(tus_options)
+                        .service(tus_create)
+                        .service(tus_offset)
+                        .service(tus_append)
+                        .service(tus_terminate)
+                        .service(dummy_uploader);
+
+                    if legacy_routes {
+                        api.service(list_private)
+                            .service(download_private)
+                            .service(remove_private)
+                    } else {
+                        api
+                    }
+                })
+                .service(Files::new("/", &static_dir).index_file("index.html"))
+        }
     };
 
-    HttpServer::new(app).bind(listen_addr)?.run().await?;
-    uploader::cleanup().await;
+    HttpServer::new(app)
+        .bind(&settings.listen_addr)?
+        .run()
+        .await?;
+
+    if let Err(e) = file_storage.flush() {
+        log::error!("Failed to flush metadata index: {}", e);
+    }
 
     Ok(())
 }
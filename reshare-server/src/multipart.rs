@@ -1,9 +1,14 @@
 //! Helper utils to deal with multipart/form-data
 //!
 
-use actix_multipart::{Multipart, MultipartError};
+use crate::{digest, file_path};
+use actix_multipart::{Field, Multipart, MultipartError};
 use actix_web::{
-    dev::HttpResponseBuilder, error::ResponseError, http::StatusCode, web::Bytes, HttpResponse,
+    dev::HttpResponseBuilder,
+    error::ResponseError,
+    http::{header, StatusCode},
+    web::Bytes,
+    HttpResponse,
 };
 use futures::{StreamExt, TryStreamExt};
 use futures_core::Stream;
@@ -12,100 +17,114 @@ use thiserror::Error;
 pub type Result<T, E = MultipartProcessingError> = std::result::Result<T, E>;
 pub type MultipartFileChunk = Result<Bytes>;
 
-pub struct MultipartFields {
-    fields: Multipart,
+/// Largest text field accepted in a form
+const MAX_TEXT_FIELD_SIZE: usize = 64 * 1024;
+
+/// Part of the form, text fields and files may come in any order
+pub enum MultipartPart<S>
+where
+    S: StreamExt<Item = MultipartFileChunk>,
+{
+    Text { name: String, value: String },
+    File(MultipartFile<S>),
 }
 
-impl From<Multipart> for MultipartFields {
-    fn from(form_data: Multipart) -> Self {
-        Self { fields: form_data }
-    }
+pub struct MultipartForm {
+    form_data: Multipart,
 }
 
-impl MultipartFields {
-    pub fn parse_files(self) -> MultipartFiles {
-        MultipartFiles::from(self.fields)
+impl From<Multipart> for MultipartForm {
+    fn from(form_data: Multipart) -> Self {
+        Self { form_data }
     }
+}
 
-    pub async fn next_text_field(&mut self, expected_field_name: &str) -> Result<Option<String>> {
-        match self.fields.try_next().await {
-            Ok(Some(mut field)) => {
-                if field
-                    .content_disposition()
-                    .as_ref()
-                    .and_then(|meta| meta.get_name())
-                    .filter(|name| *name == expected_field_name)
-                    .is_some()
-                {
-                    let mut buf = String::with_capacity(64);
-
-                    while let Some(chunk) = field.next().await {
-                        let string = chunk
-                            .map_err(|e| MultipartProcessingError::FieldError { source: e })
-                            .and_then(|bytes| {
-                                std::str::from_utf8(bytes.as_ref())
-                                    .map(|s| s.to_owned())
-                                    .map_err(|_| MultipartProcessingError::InvalidField {
-                                        name: expected_field_name.to_string(),
-                                    })
-                            })?;
-
-                        buf.push_str(&string);
-                    }
-
-                    Ok(Some(buf))
-                } else {
-                    Err(MultipartProcessingError::InvalidField {
-                        name: expected_field_name.to_owned(),
-                    })
+impl MultipartForm {
+    /// Reads the next part of the form. Text fields are read whole,
+    /// files must be streamed before the next part is requested
+    pub async fn next_part(
+        &mut self,
+    ) -> Result<Option<MultipartPart<impl StreamExt<Item = MultipartFileChunk>>>> {
+        loop {
+            let field = match self.form_data.try_next().await {
+                Ok(Some(field)) => field,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(MultipartProcessingError::FieldError { source: e }),
+            };
+
+            let content_disposition = field
+                .content_disposition()
+                .ok_or(MultipartProcessingError::InvalidField)?;
+
+            let filename = match content_disposition.get_filename() {
+                // Browsers send empty file inputs as files without a name
+                Some("") => {
+                    skip_field(field).await?;
+                    continue;
                 }
-            }
-            Ok(None) => Ok(None),
-            Err(e) => Err(MultipartProcessingError::FieldError { source: e }),
+                Some(filename) => filename.to_owned(),
+                None => {
+                    let name = content_disposition
+                        .get_name()
+                        .ok_or(MultipartProcessingError::InvalidField)?
+                        .to_owned();
+                    let value = read_text_field(field, &name).await?;
+
+                    return Ok(Some(MultipartPart::Text { name, value }));
+                }
+            };
+
+            // Relative paths place the file into subdirectories
+            let filename = file_path::normalize(&filename)
+                .ok_or(MultipartProcessingError::InvalidFilePath { path: filename })?;
+
+            // Digest of the contents may be given in the header of the part
+            let digest = digest::from_headers(field.headers())?;
+            let content_type = field
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .map(str::to_owned);
+
+            return Ok(Some(MultipartPart::File(MultipartFile {
+                filename,
+                digest,
+                content_type,
+                file_stream: StreamMap::new(field.map(|res| {
+                    res.map_err(|e| MultipartProcessingError::FileTransmissionError { source: e })
+                })),
+            })));
         }
     }
 }
 
-pub struct MultipartFiles {
-    files: Multipart,
-}
+async fn read_text_field(mut field: Field, field_name: &str) -> Result<String> {
+    let mut buf = Vec::with_capacity(64);
 
-impl From<Multipart> for MultipartFiles {
-    fn from(form_data: Multipart) -> Self {
-        Self { files: form_data }
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| MultipartProcessingError::FieldError { source: e })?;
+
+        if buf.len() + chunk.len() > MAX_TEXT_FIELD_SIZE {
+            return Err(MultipartProcessingError::FieldTooLarge {
+                name: field_name.to_owned(),
+            });
+        }
+
+        buf.extend_from_slice(&chunk);
     }
+
+    // Decoded at once, as multibyte characters may be split between chunks
+    String::from_utf8(buf).map_err(|_| MultipartProcessingError::InvalidText {
+        name: field_name.to_owned(),
+    })
 }
 
-impl MultipartFiles {
-    pub async fn next_file(
-        &mut self,
-    ) -> Result<Option<MultipartFile<impl StreamExt<Item = MultipartFileChunk>>>> {
-        match self.files.try_next().await {
-            Ok(Some(field)) => {
-                let filename = field
-                    .content_disposition()
-                    .as_ref()
-                    .and_then(|content| content.get_filename())
-                    .filter(|&name| !name.is_empty())
-                    .map(|s| {
-                        let pos = s.rfind('/').unwrap_or(0);
-                        s[pos..].to_owned()
-                    })
-                    .ok_or(MultipartProcessingError::InvalidFile)?;
-
-                Ok(Some(MultipartFile {
-                    filename,
-                    file_stream: StreamMap::new(field.map(|res| {
-                        res.map_err(|e| MultipartProcessingError::FileTransmissionError {
-                            source: e,
-                        })
-                    })),
-                }))
-            }
-            Ok(None) => Ok(None),
-            Err(e) => Err(MultipartProcessingError::FileError { source: e }),
-        }
+async fn skip_field(mut field: Field) -> Result<()> {
+    while let Some(chunk) = field.next().await {
+        chunk.map_err(|e| MultipartProcessingError::FieldError { source: e })?;
     }
+
+    Ok(())
 }
 
 pub struct MultipartFile<S>
@@ -113,6 +132,10 @@ where
     S: StreamExt<Item = MultipartFileChunk>,
 {
     pub filename: String,
+    /// Hex encoded SHA-256 digest the contents must match
+    pub digest: Option<String>,
+    /// Content type declared by the client
+    pub content_type: Option<String>,
     pub file_stream: StreamMap<S>,
 }
 
@@ -170,17 +193,26 @@ where
 
 #[derive(Debug, Error)]
 pub enum MultipartProcessingError {
-    #[error("Expected field {} is abscent", name)]
-    InvalidField { name: String },
+    #[error("Form fields must be named")]
+    InvalidField,
+
+    #[error("Field {} must be valid UTF-8 text", name)]
+    InvalidText { name: String },
+
+    #[error("Field {} exceeds {} bytes", name, MAX_TEXT_FIELD_SIZE)]
+    FieldTooLarge { name: String },
 
     #[error("Error parsing field multipart data")]
     FieldError { source: MultipartError },
 
-    #[error("Error parsing file multipart data")]
-    FileError { source: MultipartError },
+    #[error("Invalid file path {}", path)]
+    InvalidFilePath { path: String },
 
-    #[error("Invalid form input. Exected file")]
-    InvalidFile,
+    #[error("{source}")]
+    InvalidDigest {
+        #[from]
+        source: digest::InvalidDigest,
+    },
 
     #[error("File transmission error")]
     FileTransmissionError { source: MultipartError },
@@ -194,6 +226,9 @@ impl ResponseError for MultipartProcessingError {
     }
 
     fn status_code(&self) -> StatusCode {
-        StatusCode::BAD_REQUEST
+        match self {
+            Self::FieldTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
     }
 }
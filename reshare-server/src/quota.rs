@@ -0,0 +1,35 @@
+//! Limits on the amount of stored data
+//!
+
+use crate::file_storage::FileStorage;
+use crate::shard_key::ShardKey;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quotas {
+    /// Maximum number of bytes stored on the server
+    pub max_total_size: Option<u64>,
+    /// Maximum number of bytes stored in a single private shard
+    pub max_shard_size: Option<u64>,
+}
+
+impl Quotas {
+    /// Number of bytes that can still be stored in the shard,
+    /// `None` if there are no limits
+    pub fn remaining(&self, storage: &FileStorage, shard_key: &Option<ShardKey>) -> Option<u64> {
+        let total_remaining = self
+            .max_total_size
+            .map(|max_size| max_size.saturating_sub(storage.total_size()));
+
+        let shard_remaining = match shard_key {
+            Some(_) => self
+                .max_shard_size
+                .map(|max_size| max_size.saturating_sub(storage.shard_size(shard_key))),
+            None => None,
+        };
+
+        match (total_remaining, shard_remaining) {
+            (Some(total), Some(shard)) => Some(total.min(shard)),
+            (total, shard) => total.or(shard),
+        }
+    }
+}
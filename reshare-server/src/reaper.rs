@@ -0,0 +1,47 @@
+//! Background task removing files which time-to-live has run out
+//! and partial uploads abandoned by their clients
+//!
+
+use crate::tus::{PartialUploads, PARTIAL_UPLOAD_TTL};
+use crate::{blob_store::BlobStore, file_storage::FileStorage};
+use actix_web::{rt, web};
+use std::time::Duration;
+
+pub fn spawn(
+    storage: web::Data<FileStorage>,
+    blob_store: web::Data<dyn BlobStore>,
+    partial_uploads: web::Data<PartialUploads>,
+    period: Duration,
+) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            match partial_uploads.remove_stale(PARTIAL_UPLOAD_TTL).await {
+                Ok(0) => {}
+                Ok(removed) => log::info!("Removed {} abandoned partial uploads", removed),
+                Err(e) => log::error!("Failed to remove abandoned partial uploads: {}", e),
+            }
+
+            let expired = match storage.remove_expired() {
+                Ok(expired) => expired,
+                Err(e) => {
+                    log::error!("Failed to remove expired files: {}", e);
+                    continue;
+                }
+            };
+
+            for removed in expired {
+                log::info!("File expired: \"{}\"", removed.file_info.name);
+
+                if let Some(blob_id) = removed.unused_blob {
+                    if let Err(e) = blob_store.delete(&blob_id).await {
+                        log::error!("Failed to delete \"{}\": {}", removed.file_info.name, e);
+                    }
+                }
+            }
+        }
+    });
+}
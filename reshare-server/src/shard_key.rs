@@ -0,0 +1,173 @@
+//! Identifiers of private shards and keys of their contents derived from keyphrases
+//!
+
+use crate::encryption::{self, BlobKey};
+use actix_web::web;
+use argon2::Argon2;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::hash::{Hash, Hasher};
+use subtle::ConstantTimeEq;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Salted hash of a keyphrase, so that the keyphrase itself
+/// is never kept by the server
+#[derive(Clone, Eq)]
+pub struct ShardKey([u8; KEY_LEN]);
+
+impl ShardKey {
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Option<Self> {
+        let mut key = [0; KEY_LEN];
+        hex::decode_to_slice(hex_str, &mut key).ok()?;
+        Some(Self(key))
+    }
+}
+
+impl PartialEq for ShardKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Hash for ShardKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl std::fmt::Debug for ShardKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShardKey({})", self.to_hex())
+    }
+}
+
+/// Keys of the shard a request accesses
+#[derive(Debug, Clone)]
+pub struct ShardKeys {
+    pub shard_key: Option<ShardKey>,
+    /// Contents of the shard are stored unencrypted if it's not set
+    pub blob_key: Option<BlobKey>,
+}
+
+/// Derives shard keys using argon2 with the server-wide salt
+#[derive(Clone)]
+pub struct KeyHasher {
+    salt: [u8; SALT_LEN],
+    /// Key of the public shard contents
+    master_key: Option<BlobKey>,
+}
+
+impl KeyHasher {
+    pub fn new(salt: &[u8]) -> Option<Self> {
+        let mut hasher = Self {
+            salt: [0; SALT_LEN],
+            master_key: None,
+        };
+
+        if salt.len() != SALT_LEN {
+            return None;
+        }
+
+        hasher.salt.copy_from_slice(salt);
+        Some(hasher)
+    }
+
+    pub fn with_master_key(self, master_key: Option<BlobKey>) -> Self {
+        Self { master_key, ..self }
+    }
+
+    pub fn generate_salt() -> [u8; SALT_LEN] {
+        let mut salt = [0; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        salt
+    }
+
+    pub fn hash(&self, keyphrase: &str) -> Result<ShardKey, argon2::Error> {
+        let mut key = [0; KEY_LEN];
+        Argon2::default().hash_password_into(keyphrase.as_bytes(), &self.salt, &mut key)?;
+        Ok(ShardKey(key))
+    }
+
+    /// Key of the shard contents. Shard keys are kept in the index,
+    /// so it's derived with another salt rather than from the shard key
+    pub fn blob_key(&self, keyphrase: &str) -> Result<BlobKey, argon2::Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"reshare-blob-key");
+        hasher.update(self.salt);
+        let salt = hasher.finalize();
+
+        let mut key = [0; encryption::KEY_LEN];
+        Argon2::default().hash_password_into(keyphrase.as_bytes(), &salt[..SALT_LEN], &mut key)?;
+        Ok(BlobKey::new(key))
+    }
+
+    /// Hashes the keyphrase on the thread pool as it takes a while
+    pub async fn shard_key(
+        &self,
+        keyphrase: Option<String>,
+    ) -> Result<Option<ShardKey>, KeyHashError> {
+        let keyphrase = match keyphrase {
+            Some(keyphrase) => keyphrase,
+            None => return Ok(None),
+        };
+
+        let hasher = self.clone();
+        let shard_key = web::block(move || hasher.hash(&keyphrase)).await?;
+        Ok(Some(shard_key))
+    }
+
+    /// Derives both the shard key and the key of its contents,
+    /// contents of the public shard use the master key
+    pub async fn keys(&self, keyphrase: Option<String>) -> Result<ShardKeys, KeyHashError> {
+        let keyphrase = match keyphrase {
+            Some(keyphrase) => keyphrase,
+            None => {
+                return Ok(ShardKeys {
+                    shard_key: None,
+                    blob_key: self.master_key.clone(),
+                })
+            }
+        };
+
+        let hasher = self.clone();
+        let keys = web::block(move || {
+            Ok::<_, argon2::Error>(ShardKeys {
+                shard_key: Some(hasher.hash(&keyphrase)?),
+                blob_key: Some(hasher.blob_key(&keyphrase)?),
+            })
+        })
+        .await?;
+
+        Ok(keys)
+    }
+}
+
+impl std::fmt::Debug for KeyHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyHasher").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to derive shard key")]
+pub struct KeyHashError;
+
+impl From<actix_web::error::BlockingError<argon2::Error>> for KeyHashError {
+    fn from(_: actix_web::error::BlockingError<argon2::Error>) -> Self {
+        KeyHashError
+    }
+}
+
+impl actix_web::error::ResponseError for KeyHashError {
+    fn error_response(&self) -> actix_web::HttpResponse {
+        actix_web::dev::HttpResponseBuilder::new(self.status_code()).json(reshare_models::Error {
+            error_msg: self.to_string(),
+        })
+    }
+}
@@ -0,0 +1,957 @@
+//! Resumable uploads following the tus 1.0 core protocol along with
+//! its creation and termination extensions. Partial uploads are kept
+//! in a local directory until all their data is received, encrypted
+//! with the key of their shard
+//!
+
+use crate::blob_store::{BlobError, BlobStore, ByteStream, LocalBlobStore};
+use crate::encryption::{self, BlobKey, RECORD_DATA_SIZE, RECORD_OVERHEAD, RECORD_SIZE};
+use crate::shard_key::{ShardKey, ShardKeys};
+use crate::uploader::{self, UploadError, UploadOptions};
+use crate::{digest, file_path};
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::error::{BlockingError, PayloadError, ResponseError};
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use futures::lock::Mutex as WriteLock;
+use futures::{Stream, StreamExt};
+use reshare_models::tus::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Partial uploads not appended to for this long are removed
+pub const PARTIAL_UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const INFO_EXTENSION: &str = "json";
+
+pub type Result<T, E = TusError> = std::result::Result<T, E>;
+
+/// Parameters of the upload given on its creation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadInfo {
+    pub file_name: String,
+    /// Size of the whole file
+    pub length: u64,
+    /// Hex encoded key of the private shard the file goes to
+    pub shard_key: Option<String>,
+    /// Upload options from the metadata, applied once the file is received
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    /// Hex encoded SHA-256 digest the contents must match
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Fingerprint of the key the received data is encrypted with
+    #[serde(default)]
+    pub key_id: Option<String>,
+}
+
+impl UploadInfo {
+    /// Reads the `Upload-Length` and `Upload-Metadata` headers of the creation request
+    pub fn from_headers(headers: &HeaderMap, keys: &ShardKeys) -> Result<Self> {
+        let length = header_value(headers, UPLOAD_LENGTH_HEADER)
+            .and_then(|length| length.parse::<u64>().ok())
+            .ok_or(TusError::InvalidLength)?;
+
+        if length == 0 {
+            return Err(UploadError::EmptyFile.into());
+        }
+
+        let metadata = match headers.get(UPLOAD_METADATA_HEADER) {
+            Some(metadata) => {
+                parse_metadata(metadata.to_str().map_err(|_| TusError::InvalidMetadata)?)?
+            }
+            None => HashMap::new(),
+        };
+
+        let file_name = metadata
+            .get(FILENAME_KEY)
+            .filter(|name| !name.is_empty())
+            .ok_or(TusError::MissingFileName)?;
+
+        // Relative paths place the file into subdirectories
+        let file_name =
+            file_path::normalize(file_name).ok_or_else(|| TusError::InvalidFilePath {
+                path: file_name.to_owned(),
+            })?;
+
+        // Rejected before any data is sent
+        UploadOptions::parse(&metadata)?;
+
+        let digest = metadata
+            .get(DIGEST_KEY)
+            .filter(|digest| !digest.is_empty())
+            .map(|digest| digest::from_header(digest))
+            .transpose()?;
+
+        Ok(Self {
+            file_name,
+            length,
+            shard_key: keys.shard_key.as_ref().map(ShardKey::to_hex),
+            digest,
+            options: metadata,
+            key_id: keys.blob_key.as_ref().map(BlobKey::fingerprint),
+        })
+    }
+
+    pub fn options(&self) -> uploader::Result<UploadOptions> {
+        UploadOptions::parse(&self.options)
+    }
+
+    /// Key the received data is encrypted with, given the key of the shard
+    pub fn data_key<'a>(&self, blob_key: Option<&'a BlobKey>) -> Result<Option<&'a BlobKey>> {
+        match &self.key_id {
+            Some(key_id) => blob_key
+                .filter(|blob_key| blob_key.fingerprint() == *key_id)
+                .map(Some)
+                .ok_or(TusError::MissingKey),
+            None => Ok(None),
+        }
+    }
+
+    /// Uploads to private shards are only accessible with the same keyphrase
+    pub fn ensure_shard(&self, shard_key: &Option<ShardKey>) -> Result<()> {
+        let matches = match (&self.shard_key, shard_key) {
+            (None, None) => true,
+            (Some(stored), Some(shard_key)) => {
+                ShardKey::from_hex(stored).as_ref() == Some(shard_key)
+            }
+            _ => false,
+        };
+
+        if matches {
+            Ok(())
+        } else {
+            Err(TusError::NotFound)
+        }
+    }
+}
+
+/// Uploads in progress, each is kept as a data file along with its info
+pub struct PartialUploads {
+    dir: PathBuf,
+    data: LocalBlobStore,
+    /// Uploads being appended to or removed
+    active: Mutex<HashMap<String, Holder>>,
+    /// Source of the holder generations
+    generations: Mutex<u64>,
+}
+
+/// Request holding an upload
+struct Holder {
+    generation: u64,
+    /// Set for requests which can't be superseded
+    exclusive: bool,
+    /// Held while the data is written, shared by the superseding requests
+    writing: Arc<WriteLock<()>>,
+}
+
+impl PartialUploads {
+    pub fn open(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            data: LocalBlobStore::open(dir.clone())?,
+            dir,
+            active: Mutex::new(HashMap::new()),
+            generations: Mutex::new(0),
+        })
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id).with_extension(INFO_EXTENSION)
+    }
+
+    /// Creates an empty upload and returns its id
+    pub async fn create(&self, info: &UploadInfo) -> Result<String> {
+        let id = uploader::new_blob_id();
+        let info = serde_json::to_vec(info)?;
+
+        let data_path = self.data_path(&id);
+        let info_path = self.info_path(&id);
+        web::block(move || {
+            std::fs::File::create(data_path)?;
+            std::fs::write(info_path, info)
+        })
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Info of the upload along with the number of bytes received so far
+    pub async fn get(&self, id: &str) -> Result<(UploadInfo, u64)> {
+        if !uploader::is_blob_id(id) {
+            return Err(TusError::NotFound);
+        }
+
+        let data_path = self.data_path(id);
+        let info_path = self.info_path(id);
+        let (info, data_size) = web::block(move || {
+            let info = std::fs::read(info_path)?;
+            let data_size = std::fs::metadata(data_path)?.len();
+            Ok::<_, std::io::Error>((info, data_size))
+        })
+        .await?;
+
+        let info: UploadInfo = serde_json::from_slice(&info)?;
+        let offset = DataFile::received_size(data_size, info.key_id.is_some());
+        Ok((info, offset))
+    }
+
+    fn active(&self) -> MutexGuard<'_, HashMap<String, Holder>> {
+        self.active.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn next_generation(&self) -> u64 {
+        let mut generations = self
+            .generations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *generations += 1;
+        *generations
+    }
+
+    /// Reserves the upload until the guard is dropped,
+    /// so that concurrent requests don't interleave their data
+    pub fn lock(&self, id: &str) -> Result<UploadGuard<'_>> {
+        let mut active = self.active();
+
+        if active.contains_key(id) {
+            return Err(TusError::Locked);
+        }
+
+        Ok(self.hold(&mut active, id, true))
+    }
+
+    /// Reserves the upload for appending. A request appending to the upload
+    /// is superseded, as it's likely stuck on a connection the client gave up on.
+    /// The superseded request stops before writing any more data
+    pub fn lock_append(&self, id: &str) -> Result<UploadGuard<'_>> {
+        let mut active = self.active();
+
+        if active.get(id).is_some_and(|holder| holder.exclusive) {
+            return Err(TusError::Locked);
+        }
+
+        Ok(self.hold(&mut active, id, false))
+    }
+
+    fn hold(
+        &self,
+        active: &mut HashMap<String, Holder>,
+        id: &str,
+        exclusive: bool,
+    ) -> UploadGuard<'_> {
+        let generation = self.next_generation();
+        let writing = active
+            .get(id)
+            .map(|holder| holder.writing.clone())
+            .unwrap_or_default();
+
+        active.insert(
+            id.to_owned(),
+            Holder {
+                generation,
+                exclusive,
+                writing: writing.clone(),
+            },
+        );
+
+        UploadGuard {
+            uploads: self,
+            id: id.to_owned(),
+            generation,
+            writing,
+        }
+    }
+
+    /// Appends the data received at `offset` and returns the new offset.
+    /// Data received before the connection breaks is kept, so that the client can resume
+    pub async fn append<S>(
+        &self,
+        guard: &UploadGuard<'_>,
+        length: u64,
+        offset: u64,
+        data_key: Option<&BlobKey>,
+        mut payload: S,
+    ) -> Result<u64>
+    where
+        S: Stream<Item = std::result::Result<Bytes, PayloadError>> + Unpin,
+    {
+        let id = &guard.id;
+        let data_path = self.data_path(id);
+        let data_key = data_key.cloned();
+        let (mut file, mut received) = {
+            let _writing = guard.writing().await?;
+            web::block(move || DataFile::open(&data_path, data_key)).await?
+        };
+
+        if offset != received {
+            return Err(TusError::OffsetMismatch);
+        }
+
+        while let Some(chunk) = payload.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    log::debug!("Upload {} interrupted: {}", id, e);
+                    break;
+                }
+            };
+
+            let chunk_size = chunk.len() as u64;
+            if received + chunk_size > length {
+                return Err(TusError::LengthExceeded);
+            }
+
+            let _writing = guard.writing().await?;
+            file = web::block(move || file.write(&chunk).map(|_| file)).await?;
+            received += chunk_size;
+        }
+
+        // The reported offset must survive a crash
+        let _writing = guard.writing().await?;
+        web::block(move || file.finish()).await?;
+
+        Ok(received)
+    }
+
+    /// Contents of the upload received so far, decrypted with `data_key`
+    pub async fn data(&self, id: &str, data_key: Option<&BlobKey>) -> Result<ByteStream<'static>> {
+        let data = self.data.get(id).await.map_err(|e| match e {
+            BlobError::NotFound => TusError::NotFound,
+            e => TusError::Io {
+                source: std::io::Error::other(e.to_string()),
+            },
+        })?;
+
+        Ok(match data_key {
+            Some(data_key) => encryption::open_records(data, data_key),
+            None => data,
+        })
+    }
+
+    pub async fn remove(&self, id: &str) -> Result<()> {
+        let data_path = self.data_path(id);
+        let info_path = self.info_path(id);
+
+        web::block(move || {
+            std::fs::remove_file(info_path)?;
+            std::fs::remove_file(data_path)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes uploads which haven't been appended to for `max_age`
+    /// and returns the number of removed ones
+    pub async fn remove_stale(&self, max_age: Duration) -> Result<usize> {
+        let dir = self.dir.clone();
+        let stale = web::block(move || {
+            let mut stale = Vec::new();
+
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let id = entry.file_name().to_string_lossy().into_owned();
+
+                if !uploader::is_blob_id(&id) {
+                    continue;
+                }
+
+                let modified = entry.metadata()?.modified()?;
+                let age = SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default();
+
+                if age > max_age {
+                    stale.push(id);
+                }
+            }
+
+            Ok::<_, std::io::Error>(stale)
+        })
+        .await?;
+
+        let mut removed = 0;
+        for id in stale {
+            // Uploads in progress may be stale only if the client went silent
+            let _guard = match self.lock(&id) {
+                Ok(guard) => guard,
+                Err(_) => continue,
+            };
+
+            match self.remove(&id).await {
+                Ok(()) => removed += 1,
+                Err(e) => log::error!("Failed to remove partial upload {}: {}", id, e),
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Data file of a partial upload opened for appending. Encrypted data
+/// is sealed in records of `RECORD_DATA_SIZE` bytes, the last one may
+/// be shorter and is sealed again as more data comes
+struct DataFile {
+    file: std::fs::File,
+    key: Option<BlobKey>,
+    /// Data of the last record, the file is positioned at its start
+    pending: Vec<u8>,
+    /// Number of the last record
+    record: u64,
+}
+
+impl DataFile {
+    /// Number of bytes received, given the size of the data file
+    fn received_size(data_size: u64, is_encrypted: bool) -> u64 {
+        if !is_encrypted {
+            return data_size;
+        }
+
+        let full_records = data_size / RECORD_SIZE as u64;
+        let last_record = data_size % RECORD_SIZE as u64;
+        full_records * RECORD_DATA_SIZE as u64 + last_record.saturating_sub(RECORD_OVERHEAD as u64)
+    }
+
+    /// Opens the file along with the number of bytes received.
+    /// The last record is dropped if a crash left it torn
+    fn open(path: &Path, key: Option<BlobKey>) -> std::io::Result<(Self, u64)> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let data_size = file.metadata()?.len();
+
+        let key = match key {
+            Some(key) => key,
+            None => {
+                file.seek(SeekFrom::End(0))?;
+                let data_file = Self {
+                    file,
+                    key: None,
+                    pending: Vec::new(),
+                    record: 0,
+                };
+
+                return Ok((data_file, data_size));
+            }
+        };
+
+        let record = data_size / RECORD_SIZE as u64;
+        let record_start = record * RECORD_SIZE as u64;
+
+        let mut sealed = Vec::new();
+        file.seek(SeekFrom::Start(record_start))?;
+        file.read_to_end(&mut sealed)?;
+
+        let pending = if sealed.is_empty() {
+            Vec::new()
+        } else {
+            encryption::open_record(&key, record, &sealed).or_else(|e| {
+                log::warn!("Dropped torn data of {}: {}", path.display(), e);
+                file.set_len(record_start).map(|_| Vec::new())
+            })?
+        };
+
+        file.seek(SeekFrom::Start(record_start))?;
+        let received = record * RECORD_DATA_SIZE as u64 + pending.len() as u64;
+
+        let data_file = Self {
+            file,
+            key: Some(key),
+            pending,
+            record,
+        };
+
+        Ok((data_file, received))
+    }
+
+    /// Writes the full records, the rest of the data is kept until more comes
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return self.file.write_all(data),
+        };
+
+        self.pending.extend_from_slice(data);
+
+        while self.pending.len() >= RECORD_DATA_SIZE {
+            let rest = self.pending.split_off(RECORD_DATA_SIZE);
+            let record = encryption::seal_record(key, self.record, &self.pending)?;
+            self.file.write_all(&record)?;
+
+            self.pending = rest;
+            self.record += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the last record and syncs the data
+    fn finish(mut self) -> std::io::Result<()> {
+        if let Some(key) = &self.key {
+            if !self.pending.is_empty() {
+                let record = encryption::seal_record(key, self.record, &self.pending)?;
+                self.file.write_all(&record)?;
+            }
+        }
+
+        self.file.sync_data()
+    }
+}
+
+pub struct UploadGuard<'a> {
+    uploads: &'a PartialUploads,
+    id: String,
+    generation: u64,
+    writing: Arc<WriteLock<()>>,
+}
+
+impl UploadGuard<'_> {
+    fn holds(&self, active: &HashMap<String, Holder>) -> bool {
+        active
+            .get(&self.id)
+            .is_some_and(|holder| holder.generation == self.generation)
+    }
+
+    /// Waits for the data to be written by a superseded request,
+    /// the returned guard allows writing until it's dropped
+    async fn writing(&self) -> Result<futures::lock::MutexGuard<'_, ()>> {
+        let writing = self.writing.lock().await;
+
+        if self.holds(&self.uploads.active()) {
+            Ok(writing)
+        } else {
+            Err(TusError::Superseded)
+        }
+    }
+
+    /// Keeps other requests from superseding this one,
+    /// as the received upload is being stored
+    pub fn finish(&self) -> Result<()> {
+        match self.uploads.active().get_mut(&self.id) {
+            Some(holder) if holder.generation == self.generation => {
+                holder.exclusive = true;
+                Ok(())
+            }
+            _ => Err(TusError::Superseded),
+        }
+    }
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        let mut active = self.uploads.active();
+
+        if self.holds(&active) {
+            active.remove(&self.id);
+        }
+    }
+}
+
+/// Rejects requests of other protocol versions
+pub fn check_version(headers: &HeaderMap) -> Result<()> {
+    match header_value(headers, TUS_RESUMABLE_HEADER) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(TusError::UnsupportedVersion),
+    }
+}
+
+/// Offset of the data sent with the append request
+pub fn upload_offset(headers: &HeaderMap) -> Result<u64> {
+    let is_offset_content = header_value(headers, header::CONTENT_TYPE.as_str())
+        .map(|content_type| content_type.eq_ignore_ascii_case(OFFSET_CONTENT_TYPE))
+        .unwrap_or(false);
+
+    if !is_offset_content {
+        return Err(TusError::InvalidContentType);
+    }
+
+    header_value(headers, UPLOAD_OFFSET_HEADER)
+        .and_then(|offset| offset.parse().ok())
+        .ok_or(TusError::InvalidOffset)
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+/// Parses comma separated pairs of keys and base64 encoded values,
+/// the value may be omitted
+fn parse_metadata(metadata: &str) -> Result<HashMap<String, String>> {
+    let mut pairs = HashMap::new();
+
+    for pair in metadata.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => {
+                let value = base64::decode(value.trim())
+                    .ok()
+                    .and_then(|value| String::from_utf8(value).ok())
+                    .ok_or(TusError::InvalidMetadata)?;
+                (key, value)
+            }
+            None => (pair, String::new()),
+        };
+
+        pairs.insert(key.to_owned(), value);
+    }
+
+    Ok(pairs)
+}
+
+#[derive(Debug, Error)]
+pub enum TusError {
+    #[error("Only version {} of the tus protocol is supported", TUS_VERSION)]
+    UnsupportedVersion,
+
+    #[error("Upload length must be a positive number")]
+    InvalidLength,
+
+    #[error("Upload offset must be a number")]
+    InvalidOffset,
+
+    #[error("Invalid upload metadata")]
+    InvalidMetadata,
+
+    #[error("File name is missing in upload metadata")]
+    MissingFileName,
+
+    #[error("Invalid file path {}", path)]
+    InvalidFilePath { path: String },
+
+    #[error("{source}")]
+    InvalidDigest {
+        #[from]
+        source: digest::InvalidDigest,
+    },
+
+    #[error("Expected {} content type", OFFSET_CONTENT_TYPE)]
+    InvalidContentType,
+
+    #[error("Requested upload doesn't exist")]
+    NotFound,
+
+    #[error("Upload offset doesn't match the received data")]
+    OffsetMismatch,
+
+    #[error("Upload is being modified by another request")]
+    Locked,
+
+    #[error("Upload was resumed by another request")]
+    Superseded,
+
+    #[error("Key the upload is encrypted with isn't available")]
+    MissingKey,
+
+    #[error("Data exceeds the upload length")]
+    LengthExceeded,
+
+    #[error("{source}")]
+    Upload {
+        #[from]
+        source: UploadError,
+    },
+
+    #[error("Partial upload I/O failure")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+
+    #[error("Corrupted partial upload")]
+    Corrupted {
+        #[from]
+        source: serde_json::Error,
+    },
+}
+
+impl From<BlockingError<std::io::Error>> for TusError {
+    fn from(err: BlockingError<std::io::Error>) -> Self {
+        match err {
+            BlockingError::Error(e) if e.kind() == std::io::ErrorKind::NotFound => Self::NotFound,
+            BlockingError::Error(e) => Self::Io { source: e },
+            BlockingError::Canceled => Self::Io {
+                source: std::io::Error::other("Blocking operation canceled"),
+            },
+        }
+    }
+}
+
+impl ResponseError for TusError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code())
+            .header(TUS_RESUMABLE_HEADER, TUS_VERSION)
+            .json(reshare_models::Error {
+                error_msg: self.to_string(),
+            })
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            Self::InvalidLength
+            | Self::InvalidOffset
+            | Self::InvalidMetadata
+            | Self::MissingFileName
+            | Self::InvalidFilePath { .. }
+            | Self::InvalidDigest { .. } => StatusCode::BAD_REQUEST,
+            Self::InvalidContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::OffsetMismatch | Self::Superseded => StatusCode::CONFLICT,
+            Self::Locked => StatusCode::LOCKED,
+            Self::LengthExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Upload { source: err } => err.status_code(),
+            Self::MissingKey => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Io { .. } | Self::Corrupted { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 2 * RECORD_DATA_SIZE + 10;
+
+    fn key() -> BlobKey {
+        BlobKey::new([1; encryption::KEY_LEN])
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn open_uploads() -> (tempfile::TempDir, PartialUploads) {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = PartialUploads::open(dir.path().to_owned()).unwrap();
+        (dir, uploads)
+    }
+
+    async fn create(uploads: &PartialUploads, key: Option<&BlobKey>) -> String {
+        let info = UploadInfo {
+            file_name: "file".to_owned(),
+            length: LEN as u64,
+            shard_key: None,
+            options: HashMap::new(),
+            digest: None,
+            key_id: key.map(BlobKey::fingerprint),
+        };
+
+        uploads.create(&info).await.unwrap()
+    }
+
+    fn payload(data: &[u8]) -> impl Stream<Item = std::result::Result<Bytes, PayloadError>> {
+        let chunks: Vec<_> = data
+            .chunks(1000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        futures::stream::iter(chunks)
+    }
+
+    async fn append(
+        uploads: &PartialUploads,
+        id: &str,
+        offset: usize,
+        key: Option<&BlobKey>,
+        data: &[u8],
+    ) -> Result<u64> {
+        let guard = uploads.lock_append(id)?;
+        uploads
+            .append(&guard, LEN as u64, offset as u64, key, payload(data))
+            .await
+    }
+
+    async fn read(uploads: &PartialUploads, id: &str, key: Option<&BlobKey>) -> Vec<u8> {
+        let mut data = uploads.data(id, key).await.unwrap();
+        let mut collected = Vec::new();
+
+        while let Some(chunk) = data.next().await {
+            collected.extend_from_slice(&chunk.unwrap());
+        }
+
+        collected
+    }
+
+    async fn offset(uploads: &PartialUploads, id: &str) -> u64 {
+        uploads.get(id).await.unwrap().1
+    }
+
+    #[actix_rt::test]
+    async fn appends_across_record_boundaries() {
+        let data = contents(LEN);
+        // Appends end inside records, so the last record is sealed again by the next one
+        let ends = [100, RECORD_DATA_SIZE + 5, RECORD_DATA_SIZE + 6, LEN];
+
+        for key in [None, Some(key())] {
+            let (_dir, uploads) = open_uploads();
+            let id = create(&uploads, key.as_ref()).await;
+            let mut start = 0;
+
+            for &end in &ends {
+                let received = append(&uploads, &id, start, key.as_ref(), &data[start..end])
+                    .await
+                    .unwrap();
+
+                assert_eq!(received, end as u64);
+                assert_eq!(offset(&uploads, &id).await, end as u64);
+                assert_eq!(read(&uploads, &id, key.as_ref()).await, &data[..end]);
+                start = end;
+            }
+
+            let stored = std::fs::read(uploads.data_path(&id)).unwrap();
+            assert_eq!(stored == data, key.is_none());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn resumes_after_torn_last_record() {
+        let data = contents(LEN);
+        let (_dir, uploads) = open_uploads();
+        let id = create(&uploads, Some(&key())).await;
+
+        let torn_end = RECORD_DATA_SIZE + 50;
+        append(&uploads, &id, 0, Some(&key()), &data[..torn_end])
+            .await
+            .unwrap();
+
+        let data_path = uploads.data_path(&id);
+        let data_size = std::fs::metadata(&data_path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&data_path)
+            .unwrap()
+            .set_len(data_size - 5)
+            .unwrap();
+
+        // The torn record is only detected once the data file is opened
+        let reported = offset(&uploads, &id).await as usize;
+        assert!(matches!(
+            append(&uploads, &id, reported, Some(&key()), &data[reported..]).await,
+            Err(TusError::OffsetMismatch)
+        ));
+
+        let resumed = offset(&uploads, &id).await as usize;
+        assert_eq!(resumed, RECORD_DATA_SIZE);
+
+        let received = append(&uploads, &id, resumed, Some(&key()), &data[resumed..])
+            .await
+            .unwrap();
+        assert_eq!(received, LEN as u64);
+        assert_eq!(read(&uploads, &id, Some(&key())).await, data);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_mismatching_offsets() {
+        let (_dir, uploads) = open_uploads();
+        let id = create(&uploads, Some(&key())).await;
+
+        assert!(matches!(
+            append(&uploads, &id, 5, Some(&key()), b"hello").await,
+            Err(TusError::OffsetMismatch)
+        ));
+        assert_eq!(
+            append(&uploads, &id, 0, Some(&key()), b"hello")
+                .await
+                .unwrap(),
+            5
+        );
+        assert!(matches!(
+            append(&uploads, &id, 3, Some(&key()), b"lo").await,
+            Err(TusError::OffsetMismatch)
+        ));
+        assert!(matches!(
+            append(&uploads, &id, 5, Some(&key()), &contents(LEN)).await,
+            Err(TusError::LengthExceeded)
+        ));
+
+        // Data received before the length is exceeded may be kept
+        let kept = read(&uploads, &id, Some(&key())).await;
+        assert_eq!(kept.len() as u64, offset(&uploads, &id).await);
+        assert!(kept.starts_with(b"hello"));
+    }
+
+    #[actix_rt::test]
+    async fn superseded_writer_stops() {
+        let data = contents(LEN);
+
+        for key in [None, Some(key())] {
+            let (_dir, uploads) = open_uploads();
+            let id = create(&uploads, key.as_ref()).await;
+
+            // The upload is resumed by another request while the first one is receiving data,
+            // it comes along with the empty chunk following the data
+            let mut resumed = None;
+            let first = uploads.lock_append(&id).unwrap();
+            let chunks =
+                payload(&data[..10]).chain(futures::stream::once(async { Ok(Bytes::new()) }));
+            let chunks = chunks.map(|chunk| {
+                if chunk.as_ref().is_ok_and(Bytes::is_empty) {
+                    resumed = Some(uploads.lock_append(&id).unwrap());
+                }
+                chunk
+            });
+
+            let appended = uploads
+                .append(&first, LEN as u64, 0, key.as_ref(), Box::pin(chunks))
+                .await;
+            assert!(matches!(appended, Err(TusError::Superseded)));
+            assert!(matches!(first.finish(), Err(TusError::Superseded)));
+            drop(first);
+
+            // Encrypted data of the superseded request is only written once it finishes
+            let start = offset(&uploads, &id).await as usize;
+            assert_eq!(start, if key.is_some() { 0 } else { 10 });
+
+            let second = resumed.unwrap();
+            let received = uploads
+                .append(
+                    &second,
+                    LEN as u64,
+                    start as u64,
+                    key.as_ref(),
+                    payload(&data[start..]),
+                )
+                .await
+                .unwrap();
+            assert_eq!(received, LEN as u64);
+            assert_eq!(read(&uploads, &id, key.as_ref()).await, data);
+        }
+    }
+
+    #[test]
+    fn locks_uploads() {
+        let (_dir, uploads) = open_uploads();
+
+        let appending = uploads.lock_append("id").unwrap();
+        assert!(matches!(uploads.lock("id"), Err(TusError::Locked)));
+
+        // Dropping a superseded guard leaves the upload held by the new one
+        let resumed = uploads.lock_append("id").unwrap();
+        drop(appending);
+        assert!(matches!(uploads.lock("id"), Err(TusError::Locked)));
+
+        // Uploads being stored can't be superseded
+        resumed.finish().unwrap();
+        assert!(matches!(uploads.lock_append("id"), Err(TusError::Locked)));
+        drop(resumed);
+
+        let removing = uploads.lock("id").unwrap();
+        assert!(matches!(uploads.lock_append("id"), Err(TusError::Locked)));
+        drop(removing);
+        assert!(uploads.lock_append("id").is_ok());
+    }
+}
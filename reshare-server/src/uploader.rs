@@ -1,91 +1,509 @@
-use crate::multipart::{
-    MultipartFields, MultipartFileChunk, MultipartFiles, MultipartProcessingError,
-};
-use actix_multipart::Multipart;
-use actix_web::{error::BlockingError, web};
+use crate::blob_store::{BlobError, BlobStore, ByteStream, StagedBlob};
+use crate::compression;
+use crate::config::Retention;
+use crate::content_type;
+use crate::digest::{self, InvalidDigest};
+use crate::encryption::{self, BlobKey, ContentKeyHasher};
+use crate::file_path;
+use crate::file_storage::StorageError;
+use crate::multipart::MultipartProcessingError;
+use actix_web::error::PayloadError;
+use actix_web::http::{header, HeaderMap};
+use actix_web::web::Bytes;
 use futures::StreamExt;
-use once_cell::sync::OnceCell;
-use std::io::prelude::*;
-use std::path::{Path, PathBuf};
+use reshare_models::file_info::Encoding;
+use reshare_models::upload::*;
+use reshare_models::FileInfo;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
 use thiserror::Error;
 
-const SERVER_DIRECTORY_NAME: &str = "reshare_files";
-
 pub type Result<T, E = UploadError> = std::result::Result<T, E>;
 
-pub async fn save_file<S>(
+const BLOB_ID_LEN: usize = 24;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 50;
+
+/// Limits on the size of uploads, enforced while the data is received
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadLimits {
+    pub max_file_size: Option<u64>,
+    /// Maximum size of the request body, which may carry several files
+    pub max_request_size: Option<u64>,
+}
+
+impl UploadLimits {
+    /// Rejects requests declaring a larger body before it's read
+    pub fn check_content_length(&self, headers: &HeaderMap) -> Result<()> {
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok());
+
+        match (content_length, self.max_request_size) {
+            (Some(len), Some(max_size)) if len > max_size => {
+                Err(UploadError::RequestTooLarge { max_size })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects files declaring a larger size before they're received
+    pub fn check_file_size(&self, size: u64) -> Result<()> {
+        match self.max_file_size {
+            Some(max_size) if size > max_size => Err(UploadError::FileTooLarge { max_size }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sizes a single file must fit in while it's received
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeLimits {
+    /// Space left in the storage quotas
+    pub quota: Option<u64>,
+    pub upload: UploadLimits,
+    /// Bytes of the request taken by the preceding files
+    pub request_received: u64,
+}
+
+impl SizeLimits {
+    /// Checks the number of bytes of the file received so far
+    fn check_received(&self, size: u64) -> Result<()> {
+        self.upload.check_file_size(size)?;
+
+        match self.upload.max_request_size {
+            Some(max_size) if self.request_received + size > max_size => {
+                Err(UploadError::RequestTooLarge { max_size })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the number of bytes written to the blob store so far,
+    /// which is what the quotas are counted in
+    fn check_stored(&self, size: u64) -> Result<()> {
+        match self.quota {
+            Some(quota) if size > quota => Err(UploadError::QuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// File which contents are written to the blob store, but not committed yet.
+/// The contents are discarded if it's dropped
+pub struct SavedFile {
+    pub file_info: FileInfo,
+    pub blob: Box<dyn StagedBlob>,
+}
+
+/// Writes the file to the blob store. The upload is aborted
+/// as soon as the file exceeds any of the `size_limits`.
+/// The file is rejected if its contents don't match `expected_digest`.
+/// The contents are stored compressed if `compression_level` is given
+/// and encrypted if the key of the shard, `shard_key`, is
+pub async fn save_file<S, E>(
     file_name: String,
-    mut file_stream: impl std::convert::AsMut<S>,
-) -> Result<reshare_models::FileInfo>
+    file_stream: S,
+    blob_store: &dyn BlobStore,
+    size_limits: SizeLimits,
+    expected_digest: Option<&str>,
+    compression_level: Option<i32>,
+    shard_key: Option<&BlobKey>,
+) -> Result<SavedFile>
 where
-    S: StreamExt<Item = MultipartFileChunk> + Unpin,
+    S: StreamExt<Item = std::result::Result<Bytes, E>> + Unpin,
+    UploadError: From<E>,
 {
-    use rand::{distributions::Alphanumeric, Rng};
+    let blob_id = new_blob_id();
 
-    let stream = file_stream.as_mut();
-    let mut bytes_written: u64 = 0;
+    let mut hasher = Sha256::new();
+    let mut content_key_hasher = shard_key.map(|_| ContentKeyHasher::default());
+    let mut bytes_received: u64 = 0;
 
-    let actual_name: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(24)
-        .map(char::from)
-        .collect();
+    let mut head = Vec::new();
 
-    let storage_path = get_work_dir().join(&actual_name);
+    let data = file_stream.map(|chunk| {
+        let chunk = chunk.map_err(|e| BlobError::interrupted(UploadError::from(e)))?;
+        bytes_received += chunk.len() as u64;
 
-    let mut f = {
-        let storage_path = storage_path.clone();
-        web::block(|| std::fs::File::create(storage_path)).await?
+        size_limits
+            .check_received(bytes_received)
+            .map_err(BlobError::interrupted)?;
+
+        hasher.update(&chunk);
+        if let Some(content_key_hasher) = &mut content_key_hasher {
+            content_key_hasher.update(&chunk);
+        }
+
+        // Kept to detect the content type
+        let head_missing = content_type::SNIFF_LEN.saturating_sub(head.len());
+        head.extend_from_slice(&chunk[..head_missing.min(chunk.len())]);
+
+        Ok(chunk)
+    });
+
+    let data: ByteStream<'_> = Box::pin(data);
+    let data = match compression_level {
+        Some(level) => compression::compress(data, level)?,
+        None => data,
+    };
+    let blob_key = shard_key.map(|_| BlobKey::generate());
+    let data = match &blob_key {
+        Some(blob_key) => encryption::encrypt(data, blob_key),
+        None => data,
     };
 
-    while let Some(chunk) = stream.next().await {
+    let mut bytes_stored: u64 = 0;
+    let data = data.map(|chunk| {
         let chunk = chunk?;
-        let chunk_size = chunk.len();
+        bytes_stored += chunk.len() as u64;
 
-        f = web::block(move || f.write_all(&chunk).map(|_| f)).await?;
-        bytes_written += chunk_size as u64;
-    }
+        size_limits
+            .check_stored(bytes_stored)
+            .map_err(BlobError::interrupted)?;
 
-    if bytes_written == 0 {
-        {
-            let storage_path = storage_path.clone();
-            let _ = web::block(move || std::fs::remove_file(storage_path)).await;
-        }
+        Ok(chunk)
+    });
+    let data: ByteStream<'_> = Box::pin(data);
 
+    let blob = blob_store.stage(&blob_id, data).await?;
+    let digest = hex::encode(hasher.finalize());
+
+    if bytes_received == 0 {
         Err(UploadError::EmptyFile)
+    } else if expected_digest.map(|expected| !expected.eq_ignore_ascii_case(&digest)) == Some(true)
+    {
+        log::warn!("Contents of \"{}\" don't match the given digest", file_name);
+
+        Err(UploadError::DigestMismatch)
     } else {
-        Ok(reshare_models::FileInfo {
+        let sealed_keys = match (shard_key, content_key_hasher, blob_key) {
+            (Some(shard_key), Some(content_key_hasher), Some(blob_key)) => {
+                let content_key = content_key_hasher.finish();
+                Some((
+                    encryption::seal_key(shard_key, &content_key).map_err(BlobError::from)?,
+                    encryption::seal_key(&content_key, &blob_key).map_err(BlobError::from)?,
+                ))
+            }
+            _ => None,
+        };
+
+        let file_info = reshare_models::FileInfo {
+            content_type: content_type::detect(&head, &file_name),
             name: file_name,
-            size: bytes_written,
+            size: bytes_received,
             upload_date: chrono::Local::now(),
-            storage_path,
+            version: reshare_models::file_info::FIRST_VERSION,
+            expires_at: None,
+            downloads_left: None,
+            digest: Some(digest),
+            description: None,
+            tags: Vec::new(),
+            encoding: compression_level.map(|_| Encoding::Zstd),
+            stored_size: Some(blob.size()),
+            blob_id,
+            key_id: shard_key.map(BlobKey::fingerprint),
+            sealed_content_key: sealed_keys.as_ref().map(|keys| keys.0.clone()),
+            sealed_blob_key: sealed_keys.map(|keys| keys.1),
+        };
+
+        Ok(SavedFile { file_info, blob })
+    }
+}
+
+/// Moves the saved file encrypted with the key of one shard, `from`, to another,
+/// e.g. once the shard it's uploaded to is known. Only the key of the contents
+/// is sealed again, unless the other shard keeps its contents unencrypted.
+/// Then the contents are committed to be read back and deleted afterwards
+pub async fn reencrypt(
+    saved: SavedFile,
+    blob_store: &Arc<dyn BlobStore>,
+    from: &BlobKey,
+    to: Option<&BlobKey>,
+) -> Result<SavedFile> {
+    let SavedFile { file_info, blob } = saved;
+
+    if let Some(to) = to {
+        let content_key = encryption::content_key(&file_info, from).map_err(BlobError::from)?;
+        let sealed_content_key = encryption::seal_key(to, &content_key).map_err(BlobError::from)?;
+
+        return Ok(SavedFile {
+            file_info: FileInfo {
+                key_id: Some(to.fingerprint()),
+                sealed_content_key: Some(sealed_content_key),
+                ..file_info
+            },
+            blob,
+        });
+    }
+
+    let blob_key = encryption::blob_key(&file_info, from).map_err(BlobError::from)?;
+    let source = CommittedBlob::commit(blob, &file_info.blob_id, blob_store).await?;
+
+    let blob_id = new_blob_id();
+
+    let data = blob_store.get(&file_info.blob_id).await;
+    let staged = match data {
+        Ok(data) => {
+            blob_store
+                .stage(&blob_id, encryption::decrypt(data, &blob_key))
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    source.delete().await;
+    let blob = staged?;
+
+    Ok(SavedFile {
+        file_info: FileInfo {
+            stored_size: Some(blob.size()),
+            blob_id,
+            key_id: None,
+            sealed_content_key: None,
+            sealed_blob_key: None,
+            ..file_info
+        },
+        blob,
+    })
+}
+
+/// Committed blob which isn't referenced by the index yet. It's deleted
+/// when dropped unless it's kept, so that it doesn't outlive a cancelled request
+pub struct CommittedBlob {
+    blob_store: Arc<dyn BlobStore>,
+    id: Option<String>,
+}
+
+impl CommittedBlob {
+    pub async fn commit(
+        blob: Box<dyn StagedBlob>,
+        id: &str,
+        blob_store: &Arc<dyn BlobStore>,
+    ) -> Result<Self> {
+        blob.commit().await?;
+
+        Ok(Self {
+            blob_store: blob_store.clone(),
+            id: Some(id.to_owned()),
         })
     }
+
+    /// Leaves the blob in the store, as it's referenced from now on
+    pub fn keep(mut self) {
+        self.id = None;
+    }
+
+    pub async fn delete(mut self) {
+        if let Some(id) = self.id.take() {
+            delete_blob(self.blob_store.as_ref(), &id).await;
+        }
+    }
 }
 
-pub async fn cleanup() {
-    let _ = web::block(|| std::fs::remove_dir_all(get_work_dir())).await;
+impl Drop for CommittedBlob {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let blob_store = self.blob_store.clone();
+            actix_web::rt::spawn(async move { delete_blob(blob_store.as_ref(), &id).await });
+        }
+    }
 }
 
-pub struct UploadForm {
-    pub keyphrase: Option<String>,
-    pub files: MultipartFiles,
+async fn delete_blob(blob_store: &dyn BlobStore, id: &str) {
+    if let Err(e) = blob_store.delete(id).await {
+        log::error!("Failed to delete blob {}: {}", id, e);
+    }
 }
 
-impl UploadForm {
-    pub async fn try_from_multipart(form_data: Multipart) -> Result<UploadForm> {
-        let mut fields = MultipartFields::from(form_data);
+/// Random id of a blob, also used for other short-lived objects
+pub fn new_blob_id() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
 
-        let keyphrase = fields
-            .next_text_field("keyphrase")
-            .await?
-            .filter(|s| !s.is_empty());
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(BLOB_ID_LEN)
+        .map(char::from)
+        .collect()
+}
 
-        Ok(UploadForm {
-            keyphrase,
-            files: fields.parse_files(),
+/// Whether the id could have been generated by `new_blob_id`
+pub fn is_blob_id(id: &str) -> bool {
+    id.len() == BLOB_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// What to do when a file with the same name is already stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Store the file as the next version of the existing one
+    #[default]
+    NewVersion,
+    /// Store the file under a numbered name, e.g. `report(1).pdf`
+    KeepBoth,
+}
+
+/// Limits and metadata applied to the uploaded files
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    pub ttl: Option<chrono::Duration>,
+    pub max_downloads: Option<u32>,
+    pub on_conflict: ConflictPolicy,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Hex encoded SHA-256 digests of the files by their names
+    pub digests: HashMap<String, String>,
+}
+
+impl UploadOptions {
+    /// Parses the options from named values, e.g. form fields or query parameters.
+    /// Empty values are the same as missing ones, unknown names are ignored
+    pub fn parse(values: &HashMap<String, String>) -> Result<Self> {
+        let value = |key| {
+            values
+                .get(key)
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+        };
+
+        Ok(Self {
+            ttl: value(TTL_KEY).map(parse_ttl).transpose()?,
+            max_downloads: value(MAX_DOWNLOADS_KEY)
+                .map(parse_max_downloads)
+                .transpose()?,
+            on_conflict: value(ON_CONFLICT_KEY)
+                .map(parse_conflict_policy)
+                .transpose()?
+                .unwrap_or_default(),
+            description: value(DESCRIPTION_KEY).map(parse_description).transpose()?,
+            tags: value(TAGS_KEY)
+                .map(parse_tags)
+                .transpose()?
+                .unwrap_or_default(),
+            digests: HashMap::new(),
+        })
+    }
+
+    /// Parses the options of an upload form, which may also carry the digests of its files
+    pub fn parse_form(values: &HashMap<String, String>) -> Result<Self> {
+        let digests = values
+            .get(DIGESTS_FIELD)
+            .map(|digests| parse_digests(digests))
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            digests,
+            ..Self::parse(values)?
         })
     }
+
+    /// Rejects the file if its contents don't match the digest given for it
+    pub fn check_digest(&self, file_info: &FileInfo) -> Result<()> {
+        let expected = match self.digests.get(&file_info.name) {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        if file_info.digest.as_deref() == Some(expected.as_str()) {
+            Ok(())
+        } else {
+            log::warn!(
+                "Contents of \"{}\" don't match the given digest",
+                file_info.name
+            );
+            Err(UploadError::DigestMismatch)
+        }
+    }
+
+    /// Sets the limits and metadata of the uploaded file,
+    /// missing limits are taken from the retention defaults
+    pub fn apply(&self, file_info: FileInfo, retention: &Retention) -> FileInfo {
+        FileInfo {
+            expires_at: retention
+                .ttl(self.ttl)
+                .map(|ttl| file_info.upload_date + ttl),
+            downloads_left: retention.max_downloads(self.max_downloads),
+            description: self.description.clone(),
+            tags: self.tags.clone(),
+            ..file_info
+        }
+    }
+}
+
+/// Parses time-to-live given in seconds
+pub fn parse_ttl(ttl: &str) -> Result<chrono::Duration> {
+    ttl.trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|&secs| secs > 0)
+        .map(|secs| chrono::Duration::seconds(secs.into()))
+        .ok_or(UploadError::InvalidTtl)
+}
+
+/// Parses digests given as `<file name>:<digest>` lines,
+/// the names are normalized the same way as the names of the files
+fn parse_digests(digests: &str) -> Result<HashMap<String, String>> {
+    digests
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, digest) = line.split_once(':').ok_or(UploadError::InvalidDigests)?;
+            let name = file_path::normalize(name.trim()).ok_or(UploadError::InvalidDigests)?;
+            let digest = digest::from_header(digest).map_err(|_| UploadError::InvalidDigests)?;
+            Ok((name, digest))
+        })
+        .collect()
+}
+
+pub fn parse_max_downloads(max_downloads: &str) -> Result<u32> {
+    max_downloads
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|&count| count > 0)
+        .ok_or(UploadError::InvalidDownloadLimit)
+}
+
+pub fn parse_conflict_policy(policy: &str) -> Result<ConflictPolicy> {
+    match policy.trim() {
+        "new_version" => Ok(ConflictPolicy::NewVersion),
+        "keep_both" => Ok(ConflictPolicy::KeepBoth),
+        _ => Err(UploadError::InvalidConflictPolicy),
+    }
+}
+
+fn parse_description(description: &str) -> Result<String> {
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(UploadError::InvalidDescription);
+    }
+
+    Ok(description.to_owned())
+}
+
+/// Parses comma separated tags, dropping empty and repeated ones
+fn parse_tags(tags: &str) -> Result<Vec<String>> {
+    let mut parsed = Vec::new();
+
+    for tag in tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(UploadError::InvalidTags);
+        }
+
+        if !parsed.iter().any(|parsed| parsed == tag) {
+            parsed.push(tag.to_owned());
+        }
+    }
+
+    if parsed.len() > MAX_TAGS {
+        return Err(UploadError::InvalidTags);
+    }
+
+    Ok(parsed)
 }
 
 #[derive(Debug, Error)]
@@ -96,9 +514,67 @@ pub enum UploadError {
         source: MultipartProcessingError,
     },
 
+    #[error("File transmission error")]
+    Transmission {
+        #[from]
+        source: PayloadError,
+    },
+
     #[error("Empty files not allowed")]
     EmptyFile,
 
+    #[error("Invalid file path {}", path)]
+    InvalidFilePath { path: String },
+
+    #[error("{source}")]
+    InvalidDigest {
+        #[from]
+        source: InvalidDigest,
+    },
+
+    #[error("Digests must be given as <file name>:SHA-256=<base64>, one per line")]
+    InvalidDigests,
+
+    #[error("Time-to-live must be a positive number of seconds")]
+    InvalidTtl,
+
+    #[error("Download limit must be a positive number")]
+    InvalidDownloadLimit,
+
+    #[error("Conflict policy must be either \"new_version\" or \"keep_both\"")]
+    InvalidConflictPolicy,
+
+    #[error("Description must be at most {} characters long", MAX_DESCRIPTION_LEN)]
+    InvalidDescription,
+
+    #[error(
+        "At most {} tags of up to {} characters are allowed",
+        MAX_TAGS,
+        MAX_TAG_LEN
+    )]
+    InvalidTags,
+
+    #[error("Discarded as another file of the request failed")]
+    Discarded,
+
+    #[error("File contents don't match the given digest, the file may be corrupted in transit")]
+    DigestMismatch,
+
+    #[error("Storage quota exceeded")]
+    QuotaExceeded,
+
+    #[error("File exceeds the maximum size of {max_size} bytes")]
+    FileTooLarge { max_size: u64 },
+
+    #[error("Request exceeds the maximum size of {max_size} bytes")]
+    RequestTooLarge { max_size: u64 },
+
+    #[error("Failed to store the file")]
+    Storage {
+        #[from]
+        source: StorageError,
+    },
+
     #[error("Operation failed due to internal failure")]
     InternalFailure,
 }
@@ -116,28 +592,43 @@ impl actix_web::error::ResponseError for UploadError {
         use actix_web::http::StatusCode;
         match self {
             Self::Multipart { source: err } => err.status_code(),
-            Self::EmptyFile => StatusCode::BAD_REQUEST,
+            Self::Transmission { .. }
+            | Self::EmptyFile
+            | Self::InvalidFilePath { .. }
+            | Self::InvalidDigest { .. }
+            | Self::InvalidDigests
+            | Self::InvalidTtl
+            | Self::InvalidDownloadLimit
+            | Self::InvalidConflictPolicy
+            | Self::InvalidDescription
+            | Self::InvalidTags
+            | Self::DigestMismatch => StatusCode::BAD_REQUEST,
+            Self::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            Self::FileTooLarge { .. } | Self::RequestTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Self::Storage { source: err } => err.status_code(),
+            // The file itself is fine, the failure of the other one is reported instead
+            Self::Discarded => StatusCode::CONFLICT,
             Self::InternalFailure => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-impl From<BlockingError<std::io::Error>> for UploadError {
-    fn from(err: BlockingError<std::io::Error>) -> UploadError {
-        log::error!("{}", err);
-        UploadError::InternalFailure
+impl From<BlobError> for UploadError {
+    fn from(err: BlobError) -> UploadError {
+        match err {
+            BlobError::Interrupted { source } => match source.downcast() {
+                Ok(err) => *err,
+                Err(err) => {
+                    log::error!("{}", err);
+                    UploadError::InternalFailure
+                }
+            },
+            err => {
+                log::error!("{}", err);
+                UploadError::InternalFailure
+            }
+        }
     }
 }
-
-fn get_work_dir() -> &'static Path {
-    static DIR: OnceCell<PathBuf> = OnceCell::new();
-
-    DIR.get_or_init(|| {
-        let root_path = dirs_next::home_dir().unwrap_or(PathBuf::from("/"));
-        let dir_path = root_path.join(SERVER_DIRECTORY_NAME);
-        let _ = std::fs::create_dir(&dir_path);
-
-        dir_path
-    })
-    .as_path()
-}
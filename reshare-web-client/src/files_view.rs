@@ -1,7 +1,7 @@
 use crate::utils::NeqAssign;
 
 use crate::storage_state::StorageState;
-use reshare_models::FileInfo;
+use reshare_models::{FileInfo, SortKey, SortOrder};
 use std::rc::Rc;
 use yew::prelude::*;
 use yew::Properties;
@@ -10,6 +10,8 @@ use yew::Properties;
 pub struct FetchedFiles {
     pub storage_state: StorageState,
     pub file_list: Vec<FileInfo>,
+    pub sort_key: SortKey,
+    pub sort_order: SortOrder,
 }
 
 pub enum FilesViewMode {
@@ -38,6 +40,10 @@ impl Default for FilesViewMode {
 pub struct Mode {
     #[prop_or_default]
     pub mode: Rc<FilesViewMode>,
+    #[prop_or_default]
+    pub on_delete: Callback<String>,
+    #[prop_or_default]
+    pub on_sort: Callback<SortKey>,
 }
 
 pub struct FilesView {
@@ -83,7 +89,7 @@ impl Component for FilesView {
                 </div>
             },
             FilesViewMode::ShowFiles(ref fetched_files) => {
-                let download_url_root = fetched_files.storage_state.download_url_root();
+                let storage_state = &fetched_files.storage_state;
 
                 let contents = if fetched_files.file_list.is_empty() {
                     html! {
@@ -98,14 +104,16 @@ impl Component for FilesView {
                         <table class="highlight">
                             <thead>
                                 <tr>
-                                    <th>{ "File name" }</th>
-                                    <th>{ "Upload date" }</th>
-                                    <th>{ "Size" }</th>
+                                    { self.sortable_header("File name", SortKey::Name, fetched_files) }
+                                    { self.sortable_header("Upload date", SortKey::UploadDate, fetched_files) }
+                                    { self.sortable_header("Size", SortKey::Size, fetched_files) }
+                                    <th>{ "Type" }</th>
                                     <th>{ "Download" } </th>
+                                    <th>{ "Delete" } </th>
                                 </tr>
                             </thead>
                             <tbody>
-                                { for fetched_files.file_list.iter().map(|f| into_table_row(f, &download_url_root)) }
+                                { for fetched_files.file_list.iter().map(|f| into_table_row(f, storage_state, &self.view_mode.on_delete)) }
                             </tbody>
                         </table>
                     }
@@ -131,7 +139,39 @@ impl Component for FilesView {
     }
 }
 
-fn into_table_row(file_info: &FileInfo, download_url_root: &str) -> Html {
+impl FilesView {
+    /// Header of the column the files can be ordered by, the current order is marked with an arrow
+    fn sortable_header(
+        &self,
+        title: &str,
+        sort_key: SortKey,
+        fetched_files: &FetchedFiles,
+    ) -> Html {
+        let arrow = if fetched_files.sort_key == sort_key {
+            match fetched_files.sort_order {
+                SortOrder::Asc => "arrow_drop_up",
+                SortOrder::Desc => "arrow_drop_down",
+            }
+        } else {
+            ""
+        };
+
+        let sort_cb = self.view_mode.on_sort.reform(move |_| sort_key);
+
+        html! {
+            <th class="sortable-header" onclick=sort_cb>
+                { title }
+                <i class="tiny material-icons">{ arrow }</i>
+            </th>
+        }
+    }
+}
+
+fn into_table_row(
+    file_info: &FileInfo,
+    storage_state: &StorageState,
+    on_delete: &Callback<String>,
+) -> Html {
     use indicatif::HumanBytes;
     let human_readable_size = HumanBytes(file_info.size);
     let human_readable_date = file_info
@@ -139,16 +179,43 @@ fn into_table_row(file_info: &FileInfo, download_url_root: &str) -> Html {
         .format("%Y %b %d - %H:%M:%S")
         .to_string();
 
-    let download_path = format!("{}{}", download_url_root, file_info.name);
+    let download_path = storage_state.file_url(&file_info.name);
+
+    let file_name = file_info.name.clone();
+    let delete_cb = on_delete.reform(move |_| file_name.clone());
+
+    let download_icon = html! {
+        <i class="waves-effect waves-green circle material-icons ">
+            { "file_download" }
+        </i>
+    };
+
+    // Private files are requested with a form, so that the key phrase doesn't end up in the url
+    let download_link = match storage_state.key_phrase() {
+        Some(key_phrase) => html! {
+            <form method="post" action={ download_path }>
+                <input type="hidden" name="keyphrase" value={ key_phrase }/>
+                <button type="submit" class="btn-flat">{ download_icon }</button>
+            </form>
+        },
+        None => html! {
+            <a href={ download_path } >{ download_icon }</a>
+        },
+    };
+
     html! {
         <tr>
             <td>{ &file_info.name }</td>
             <td>{ human_readable_date }</td>
             <td>{ human_readable_size }</td>
+            <td>{ file_info.content_type.as_deref().unwrap_or("-") }</td>
+            <td class="centered-cell">
+                { download_link }
+            </td>
             <td class="centered-cell">
-                <a href={ download_path } >
-                    <i class="waves-effect waves-green circle material-icons ">
-                        { "file_download" }
+                <a onclick=delete_cb>
+                    <i class="waves-effect waves-red circle material-icons ">
+                        { "delete" }
                     </i>
                 </a>
             </td>
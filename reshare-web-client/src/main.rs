@@ -4,7 +4,7 @@ mod uploader;
 mod utils;
 
 use files_view::{FetchedFiles, FilesView, FilesViewMode};
-use reshare_models::FileInfo;
+use reshare_models::{FileInfo, SortKey, SortOrder, KEYPHRASE_HEADER};
 use std::{cell::RefCell, rc::Rc};
 use storage_state::StorageState;
 use uploader::Uploader;
@@ -24,6 +24,9 @@ enum Msg {
     GetFiles,
     KeyPhraseUpdated(String),
     ReceivedFiles(FetchedFiles),
+    DeleteFile(String),
+    SortBy(SortKey),
+    FileDeleted,
     UploadButtonPressed,
 }
 
@@ -49,7 +52,10 @@ impl ViewState {
 struct ReshareModel {
     link: ComponentLink<Self>,
     storage_state: StorageState,
+    sort_key: SortKey,
+    sort_order: SortOrder,
     fetch_task: Option<FetchTask>,
+    delete_task: Option<FetchTask>,
     // RefCell is used here for optimization purposes
     files_view_mode: RefCell<Option<FilesViewMode>>,
     main_view_state: ViewState,
@@ -63,7 +69,10 @@ impl Component for ReshareModel {
         let model = Self {
             link,
             storage_state: StorageState::Public,
+            sort_key: SortKey::default(),
+            sort_order: SortOrder::default(),
             fetch_task: None,
+            delete_task: None,
             files_view_mode: RefCell::new(None),
             main_view_state: ViewState::Downloader,
         };
@@ -79,7 +88,16 @@ impl Component for ReshareModel {
                     return false;
                 }
 
-                let req = match Request::get(self.storage_state.fetch_files_url()).body(Nothing) {
+                let req = Request::get(
+                    self.storage_state
+                        .fetch_files_url(self.sort_key, self.sort_order),
+                );
+                let req = match self.storage_state.key_phrase_header() {
+                    Some(key_phrase) => req.header(KEYPHRASE_HEADER, key_phrase),
+                    None => req,
+                };
+
+                let req = match req.body(Nothing) {
                     Ok(req) => req,
                     Err(e) => {
                         log::error!("{}", e);
@@ -88,6 +106,8 @@ impl Component for ReshareModel {
                 };
 
                 let storage_state = self.storage_state.clone();
+                let sort_key = self.sort_key;
+                let sort_order = self.sort_order;
 
                 let callback = self.link.callback(
                     move |response: Response<Json<Result<Vec<FileInfo>, anyhow::Error>>>| {
@@ -106,6 +126,8 @@ impl Component for ReshareModel {
                         Msg::ReceivedFiles(FetchedFiles {
                             file_list,
                             storage_state: storage_state.clone(),
+                            sort_key,
+                            sort_order,
                         })
                     },
                 );
@@ -121,6 +143,57 @@ impl Component for ReshareModel {
                 self.fetch_task = None;
                 true
             }
+            Msg::DeleteFile(file_name) => {
+                if self.delete_task.is_some() {
+                    return false;
+                }
+
+                let url = self.storage_state.file_url(&file_name);
+
+                let req = Request::delete(url);
+                let req = match self.storage_state.key_phrase_header() {
+                    Some(key_phrase) => req.header(KEYPHRASE_HEADER, key_phrase),
+                    None => req,
+                };
+
+                let req = match req.body(Nothing) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return false;
+                    }
+                };
+
+                let callback = self.link.callback(move |response: Response<Nothing>| {
+                    if !response.status().is_success() {
+                        log::error!("Failed to delete {}: {}", file_name, response.status());
+                    }
+
+                    Msg::FileDeleted
+                });
+
+                let delete_task = FetchService::fetch(req, callback).expect("Fetching must work");
+                self.delete_task = Some(delete_task);
+
+                false
+            }
+            Msg::SortBy(sort_key) => {
+                // Clicking the same column again flips the order
+                if self.sort_key == sort_key {
+                    self.sort_order = self.sort_order.reversed();
+                } else {
+                    self.sort_key = sort_key;
+                    self.sort_order = SortOrder::Asc;
+                }
+
+                self.link.send_message(Msg::GetFiles);
+                false
+            }
+            Msg::FileDeleted => {
+                self.delete_task = None;
+                self.link.send_message(Msg::GetFiles);
+                false
+            }
             Msg::KeyPhraseUpdated(key_phrase) => {
                 if key_phrase.is_empty() {
                     self.storage_state = StorageState::Public;
@@ -159,7 +232,10 @@ impl Component for ReshareModel {
                 <div class="row spacer"></div>
                 <div class="divider"></div>
 
-                <FilesView mode=files_view_mode />
+                <FilesView
+                    mode=files_view_mode
+                    on_delete=self.link.callback(Msg::DeleteFile)
+                    on_sort=self.link.callback(Msg::SortBy) />
             </div>
 
             { self.render_upload_button() }
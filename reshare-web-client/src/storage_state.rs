@@ -1,3 +1,5 @@
+use reshare_models::{SortKey, SortOrder};
+
 #[derive(Debug, Clone)]
 pub enum StorageState {
     Public,
@@ -5,23 +7,35 @@ pub enum StorageState {
 }
 
 impl StorageState {
-    pub fn fetch_files_url(&self) -> String {
-        match &self {
-            StorageState::Public => "/api/list".to_owned(),
-            StorageState::Private { key_phrase } => {
-                format!("/api/private/{}", urlencoding::encode(key_phrase))
-            }
-        }
+    pub fn fetch_files_url(&self, sort_key: SortKey, sort_order: SortOrder) -> String {
+        format!(
+            "/api/list?sort={}&order={}",
+            sort_key.as_str(),
+            sort_order.as_str()
+        )
     }
 
     pub fn download_url_root(&self) -> String {
+        "/api/download/".to_owned()
+    }
+
+    /// Url of the file, directories in its path are kept as separate segments
+    pub fn file_url(&self, file_name: &str) -> String {
+        let path: Vec<_> = file_name.split('/').map(urlencoding::encode).collect();
+        format!("{}{}", self.download_url_root(), path.join("/"))
+    }
+
+    pub fn key_phrase(&self) -> Option<&str> {
         match &self {
-            StorageState::Public => "/api/download/".to_owned(),
-            StorageState::Private { key_phrase } => {
-                format!("/api/private/{}/", urlencoding::encode(key_phrase))
-            }
+            StorageState::Public => None,
+            StorageState::Private { key_phrase } => Some(key_phrase),
         }
     }
+
+    /// Value of the header identifying the private storage
+    pub fn key_phrase_header(&self) -> Option<String> {
+        self.key_phrase().map(urlencoding::encode)
+    }
 }
 
 impl std::fmt::Display for StorageState {